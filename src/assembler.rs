@@ -0,0 +1,1018 @@
+use crate::chip8::{Instruction, Register, PROGRAM_MEMORY_OFFSET};
+use crate::disasm::Syntax;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+// how many macro expansions a program may go through (catches recursive macros)
+const MAX_EXPANSIONS: usize = 10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error<S: Into<String>>(&self, message: S) -> AssembleError {
+        AssembleError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    fn is(&self, text: &str) -> bool {
+        self.text.eq_ignore_ascii_case(text)
+    }
+}
+
+// where a label address has to be patched in once it is known
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FixupKind {
+    Nnn,  // low 12 bits of an opcode
+    Long, // a whole 16 bit word
+}
+
+struct Fixup {
+    offset: usize,
+    kind: FixupKind,
+    token: Token,
+}
+
+// an operand that may name a label defined further down
+enum Target {
+    Known(u16),
+    Label(Token),
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Assembler {
+    output: Vec<u8>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    expansions: usize,
+}
+
+// assemble a program into the bytes of a ROM loaded at 0x200
+pub fn assemble(source: &str, syntax: Syntax) -> Result<Vec<u8>, AssembleError> {
+    let mut assembler = Assembler {
+        output: Vec::new(),
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        expansions: 0,
+    };
+
+    match syntax {
+        Syntax::Cowgod => assembler.cowgod(source)?,
+        Syntax::Octo => assembler.octo(source)?,
+    }
+    assembler.resolve()?;
+    Ok(assembler.output)
+}
+
+// split a source into tokens, `separators` end a token as well as whitespace
+fn tokenize(source: &str, comment: char, separators: &[char]) -> Vec<Vec<Token>> {
+    let mut lines = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let mut tokens = Vec::new();
+        let mut current: Option<Token> = None;
+
+        for (column, c) in line.chars().enumerate() {
+            if c == comment {
+                break;
+            }
+            if c.is_whitespace() || separators.contains(&c) {
+                tokens.extend(current.take());
+                continue;
+            }
+            current
+                .get_or_insert_with(|| Token {
+                    text: String::new(),
+                    line: number + 1,
+                    column: column + 1,
+                })
+                .text
+                .push(c);
+        }
+        tokens.extend(current.take());
+        lines.push(tokens);
+    }
+    lines
+}
+
+fn parse_number(token: &Token) -> Option<i64> {
+    let text = token.text.as_str();
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let lower = text.to_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(hex) = lower.strip_prefix('$') {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        lower.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(token: &Token) -> Option<Register> {
+    let text = token.text.to_lowercase();
+    let digit = text.strip_prefix('v')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn register(token: &Token) -> Result<Register, AssembleError> {
+    parse_register(token)
+        .ok_or_else(|| token.error(format!("expected a register, found `{}`", token.text)))
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+impl Assembler {
+    fn address(&self) -> u16 {
+        (PROGRAM_MEMORY_OFFSET + self.output.len()) as u16
+    }
+
+    fn value(&self, token: &Token) -> Result<i64, AssembleError> {
+        if let Some(value) = parse_number(token) {
+            return Ok(value);
+        }
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(*value as i64);
+        }
+        if let Some(value) = self.labels.get(&token.text) {
+            return Ok(*value as i64);
+        }
+        Err(token.error(format!("unknown value `{}`", token.text)))
+    }
+
+    fn ranged(&self, token: &Token, min: i64, max: i64) -> Result<i64, AssembleError> {
+        let value = self.value(token)?;
+        if value < min || value > max {
+            return Err(token.error(format!(
+                "`{}` is out of range ({} to {})",
+                token.text, min, max
+            )));
+        }
+        Ok(value)
+    }
+
+    // bytes accept negative numbers as their two's complement
+    fn byte(&self, token: &Token) -> Result<u8, AssembleError> {
+        Ok(self.ranged(token, -128, 255)? as u8)
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u8, AssembleError> {
+        Ok(self.ranged(token, 0, 15)? as u8)
+    }
+
+    fn address_operand(&self, token: &Token) -> Result<Target, AssembleError> {
+        if parse_number(token).is_some() || self.constants.contains_key(&token.text) {
+            return Ok(Target::Known(self.ranged(token, 0, 0xFFFF)? as u16));
+        }
+        if let Some(address) = self.labels.get(&token.text) {
+            return Ok(Target::Known(*address));
+        }
+        if !is_identifier(&token.text) {
+            return Err(token.error(format!("expected an address, found `{}`", token.text)));
+        }
+        Ok(Target::Label(token.clone()))
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.output.extend(instruction.encode());
+    }
+
+    // emit an instruction taking an address, `build` gets the address when known
+    fn emit_address<F>(&mut self, operand: &Token, build: F) -> Result<(), AssembleError>
+    where
+        F: Fn(u16) -> Instruction,
+    {
+        let long = matches!(build(0), Instruction::LoadILong(_));
+        match self.address_operand(operand)? {
+            Target::Known(address) => {
+                if !long && address > 0xFFF {
+                    return Err(
+                        operand.error(format!("address {:#X} doesn't fit in 12 bits", address))
+                    );
+                }
+                self.emit(build(address));
+            }
+            Target::Label(token) => {
+                let (offset, kind) = if long {
+                    (self.output.len() + 2, FixupKind::Long)
+                } else {
+                    (self.output.len(), FixupKind::Nnn)
+                };
+                self.fixups.push(Fixup {
+                    offset,
+                    kind,
+                    token,
+                });
+                self.emit(build(0));
+            }
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, token: &Token) -> Result<(), AssembleError> {
+        if !is_identifier(&token.text) {
+            return Err(token.error(format!("invalid label name `{}`", token.text)));
+        }
+        if self.labels.contains_key(&token.text) {
+            return Err(token.error(format!("label `{}` is defined twice", token.text)));
+        }
+        self.labels.insert(token.text.clone(), self.address());
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &Token, value: &Token) -> Result<(), AssembleError> {
+        if !is_identifier(&name.text) {
+            return Err(name.error(format!("invalid constant name `{}`", name.text)));
+        }
+        let value = self.ranged(value, -0x8000, 0xFFFF)? as u16;
+        self.constants.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn expand(&mut self, token: &Token, arguments: &[Token]) -> Result<Vec<Token>, AssembleError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error("too many macro expansions (is a macro calling itself?)"));
+        }
+
+        let definition = &self.macros[&token.text];
+        if arguments.len() != definition.params.len() {
+            return Err(token.error(format!(
+                "macro `{}` takes {} arguments but {} were given",
+                token.text,
+                definition.params.len(),
+                arguments.len()
+            )));
+        }
+        let body = definition
+            .body
+            .iter()
+            .map(|body_token| {
+                match definition.params.iter().position(|p| *p == body_token.text) {
+                    // keep the position of the argument for error messages
+                    Some(index) => arguments[index].clone(),
+                    None => body_token.clone(),
+                }
+            })
+            .collect();
+        Ok(body)
+    }
+
+    fn resolve(&mut self) -> Result<(), AssembleError> {
+        for fixup in &self.fixups {
+            let address = match self.labels.get(&fixup.token.text) {
+                Some(address) => *address,
+                None => {
+                    return Err(fixup
+                        .token
+                        .error(format!("undefined label `{}`", fixup.token.text)))
+                }
+            };
+            match fixup.kind {
+                FixupKind::Nnn => {
+                    if address > 0xFFF {
+                        return Err(fixup.token.error(format!(
+                            "label `{}` at {:#X} doesn't fit in 12 bits",
+                            fixup.token.text, address
+                        )));
+                    }
+                    self.output[fixup.offset] |= (address >> 8) as u8;
+                    self.output[fixup.offset + 1] = address as u8;
+                }
+                FixupKind::Long => {
+                    self.output[fixup.offset..fixup.offset + 2]
+                        .copy_from_slice(&address.to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Cowgod style: one mnemonic per line with comma separated operands, `;` comments
+    fn cowgod(&mut self, source: &str) -> Result<(), AssembleError> {
+        let mut lines: VecDeque<Vec<Token>> = tokenize(source, ';', &[',']).into();
+
+        while let Some(mut tokens) = lines.pop_front() {
+            // a line can start with a label
+            if let Some(first) = tokens.first() {
+                if let Some(name) = first.text.strip_suffix(':') {
+                    let label = Token {
+                        text: name.to_string(),
+                        ..first.clone()
+                    };
+                    self.define_label(&label)?;
+                    tokens.remove(0);
+                }
+            }
+            let (head, operands) = match tokens.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+
+            if operands.len() == 2 && operands[0].is("EQU") {
+                self.define_constant(head, &operands[1])?;
+                continue;
+            }
+
+            if head.is("MACRO") {
+                let (name, params) = operands
+                    .split_first()
+                    .ok_or_else(|| head.error("expected a macro name"))?;
+                // the body goes on until ENDM, one line after the other
+                let mut body = Vec::new();
+                loop {
+                    let line = lines
+                        .pop_front()
+                        .ok_or_else(|| head.error("macro is missing its ENDM"))?;
+                    if line.first().map(|t| t.is("ENDM")).unwrap_or(false) {
+                        break;
+                    }
+                    // lines are kept apart with a marker token
+                    body.extend(line);
+                    body.push(Token {
+                        text: "\n".to_string(),
+                        line: 0,
+                        column: 0,
+                    });
+                }
+                self.macros.insert(
+                    name.text.clone(),
+                    Macro {
+                        params: params.iter().map(|p| p.text.clone()).collect(),
+                        body,
+                    },
+                );
+                continue;
+            }
+
+            if self.macros.contains_key(&head.text) {
+                let body = self.expand(head, operands)?;
+                for line in body.split(|t| t.text == "\n").rev() {
+                    lines.push_front(line.to_vec());
+                }
+                continue;
+            }
+
+            self.cowgod_statement(head, operands)?;
+        }
+        Ok(())
+    }
+
+    fn cowgod_statement(&mut self, head: &Token, operands: &[Token]) -> Result<(), AssembleError> {
+        let count = |expected: usize| -> Result<(), AssembleError> {
+            if operands.len() != expected {
+                return Err(head.error(format!(
+                    "`{}` takes {} operands but {} were given",
+                    head.text,
+                    expected,
+                    operands.len()
+                )));
+            }
+            Ok(())
+        };
+        let reg = |index: usize| register(&operands[index]);
+        let is_reg = |index: usize| parse_register(&operands[index]).is_some();
+        let is = |index: usize, text: &str| operands[index].is(text);
+
+        let mnemonic = head.text.to_uppercase();
+        let instruction = match mnemonic.as_str() {
+            "DB" => {
+                for operand in operands {
+                    let value = self.byte(operand)?;
+                    self.output.push(value);
+                }
+                return Ok(());
+            }
+            "DW" => {
+                for operand in operands {
+                    let value = self.ranged(operand, -0x8000, 0xFFFF)? as u16;
+                    self.output.extend(value.to_be_bytes());
+                }
+                return Ok(());
+            }
+            "CLS" => Instruction::ClearDisplay,
+            "RET" => Instruction::Return,
+            "SCR" => Instruction::ScrollRight,
+            "SCL" => Instruction::ScrollLeft,
+            "EXIT" => Instruction::Exit,
+            "LOW" => Instruction::LowRes,
+            "HIGH" => Instruction::HighRes,
+            "AUDIO" => Instruction::LoadAudio,
+            "SCD" => {
+                count(1)?;
+                Instruction::ScrollDown(self.nibble(&operands[0])?)
+            }
+            "SCU" => {
+                count(1)?;
+                Instruction::ScrollUp(self.nibble(&operands[0])?)
+            }
+            "PLANE" => {
+                count(1)?;
+                Instruction::SelectPlanes(self.ranged(&operands[0], 0, 3)? as u8)
+            }
+            "PITCH" => {
+                count(1)?;
+                Instruction::SetPitch(reg(0)?)
+            }
+            "JP" if operands.len() == 2 => {
+                if !is(0, "V0") {
+                    return Err(operands[0].error("only V0 can be added to a jump"));
+                }
+                return self.emit_address(&operands[1], Instruction::JumpPlusZero);
+            }
+            "JP" => {
+                count(1)?;
+                return self.emit_address(&operands[0], Instruction::Jump);
+            }
+            "CALL" => {
+                count(1)?;
+                return self.emit_address(&operands[0], Instruction::Call);
+            }
+            "SE" | "SNE" => {
+                count(2)?;
+                let x = reg(0)?;
+                match (mnemonic.as_str(), is_reg(1)) {
+                    ("SE", true) => Instruction::SkipIfEqual(x, reg(1)?),
+                    ("SE", false) => Instruction::SkipIfEqualsByte(x, self.byte(&operands[1])?),
+                    (_, true) => Instruction::SkipIfNotEqual(x, reg(1)?),
+                    (_, false) => Instruction::SkipIfNotEqualsByte(x, self.byte(&operands[1])?),
+                }
+            }
+            "SAVE" | "LOAD" => {
+                count(2)?;
+                match mnemonic.as_str() {
+                    "SAVE" => Instruction::SaveRange(reg(0)?, reg(1)?),
+                    _ => Instruction::LoadRange(reg(0)?, reg(1)?),
+                }
+            }
+            "OR" | "AND" | "XOR" | "SUB" | "SUBN" => {
+                count(2)?;
+                let (x, y) = (reg(0)?, reg(1)?);
+                match mnemonic.as_str() {
+                    "OR" => Instruction::Or(x, y),
+                    "AND" => Instruction::And(x, y),
+                    "XOR" => Instruction::Xor(x, y),
+                    "SUB" => Instruction::Sub(x, y),
+                    _ => Instruction::ReverseSub(x, y),
+                }
+            }
+            "SHR" | "SHL" => {
+                // Vy defaults to Vx like most assemblers do
+                let x = reg(0)?;
+                let y = match operands.len() {
+                    1 => x,
+                    _ => {
+                        count(2)?;
+                        reg(1)?
+                    }
+                };
+                match mnemonic.as_str() {
+                    "SHR" => Instruction::ShiftRight(x, y),
+                    _ => Instruction::ShiftLeft(x, y),
+                }
+            }
+            "RND" => {
+                count(2)?;
+                Instruction::Random(reg(0)?, self.byte(&operands[1])?)
+            }
+            "DRW" => {
+                count(3)?;
+                match self.nibble(&operands[2])? {
+                    0 => Instruction::DrawLarge(reg(0)?, reg(1)?),
+                    n => Instruction::Draw(reg(0)?, reg(1)?, n),
+                }
+            }
+            "SKP" => {
+                count(1)?;
+                Instruction::SkipIfPressed(reg(0)?)
+            }
+            "SKNP" => {
+                count(1)?;
+                Instruction::SkipIfNotPressed(reg(0)?)
+            }
+            "ADD" => {
+                count(2)?;
+                if is(0, "I") {
+                    Instruction::AddToI(reg(1)?)
+                } else if is_reg(1) {
+                    Instruction::Add(reg(0)?, reg(1)?)
+                } else {
+                    Instruction::AddByte(reg(0)?, self.byte(&operands[1])?)
+                }
+            }
+            "LD" if operands.len() == 3 && is(0, "I") && is(1, "LONG") => {
+                return self.emit_address(&operands[2], Instruction::LoadILong);
+            }
+            "LD" => {
+                count(2)?;
+                if is(0, "I") {
+                    return self.emit_address(&operands[1], Instruction::LoadI);
+                }
+                if is_reg(0) {
+                    let x = reg(0)?;
+                    if is_reg(1) {
+                        Instruction::Move(x, reg(1)?)
+                    } else if is(1, "DT") {
+                        Instruction::LoadDelayTimer(x)
+                    } else if is(1, "K") {
+                        Instruction::WaitForKeyPress(x)
+                    } else if is(1, "[I]") {
+                        Instruction::LoadRegisters(x)
+                    } else if is(1, "R") {
+                        Instruction::LoadFlags(x)
+                    } else {
+                        Instruction::LoadByte(x, self.byte(&operands[1])?)
+                    }
+                } else {
+                    let x = reg(1)?;
+                    match operands[0].text.to_uppercase().as_str() {
+                        "DT" => Instruction::SetDelayTimer(x),
+                        "ST" => Instruction::SetSoundTimer(x),
+                        "F" => Instruction::LoadSprite(x),
+                        "HF" => Instruction::LoadLargeSprite(x),
+                        "B" => Instruction::BCDRepresentation(x),
+                        "[I]" => Instruction::StoreRegisters(x),
+                        "R" => Instruction::StoreFlags(x),
+                        _ => {
+                            return Err(operands[0]
+                                .error(format!("can't load into `{}`", operands[0].text)))
+                        }
+                    }
+                }
+            }
+            _ => return Err(head.error(format!("unknown instruction `{}`", head.text))),
+        };
+
+        // every other mnemonic takes no operands
+        if operands.len() > instruction_operands(&instruction) {
+            return Err(operands[0].error(format!("unexpected operand `{}`", operands[0].text)));
+        }
+        self.emit(instruction);
+        Ok(())
+    }
+
+    // Octo style: a free form stream of statements, `#` comments
+    fn octo(&mut self, source: &str) -> Result<(), AssembleError> {
+        let mut tokens: VecDeque<Token> =
+            tokenize(source, '#', &[]).into_iter().flatten().collect();
+
+        while let Some(token) = tokens.pop_front() {
+            let mut next = |what: &str| {
+                tokens
+                    .pop_front()
+                    .ok_or_else(|| token.error(format!("expected {} after `{}`", what, token.text)))
+            };
+
+            match token.text.as_str() {
+                ":" => {
+                    let name = next("a label name")?;
+                    self.define_label(&name)?;
+                }
+                ":const" => {
+                    let name = next("a constant name")?;
+                    let value = next("a value")?;
+                    self.define_constant(&name, &value)?;
+                }
+                ":byte" => {
+                    let value = next("a value")?;
+                    let value = self.byte(&value)?;
+                    self.output.push(value);
+                }
+                ":call" => {
+                    let target = next("an address")?;
+                    self.emit_address(&target, Instruction::Call)?;
+                }
+                ":macro" => {
+                    let name = next("a macro name")?;
+                    let mut params = Vec::new();
+                    loop {
+                        let param = next("`{`")?;
+                        if param.text == "{" {
+                            break;
+                        }
+                        params.push(param.text);
+                    }
+                    let mut body = Vec::new();
+                    let mut depth = 1;
+                    loop {
+                        let body_token = next("`}`")?;
+                        match body_token.text.as_str() {
+                            "{" => depth += 1,
+                            "}" if depth == 1 => break,
+                            "}" => depth -= 1,
+                            _ => {}
+                        }
+                        body.push(body_token);
+                    }
+                    self.macros.insert(name.text, Macro { params, body });
+                }
+                _ if self.macros.contains_key(&token.text) => {
+                    let count = self.macros[&token.text].params.len();
+                    let mut arguments = Vec::new();
+                    for _ in 0..count {
+                        arguments.push(next("a macro argument")?);
+                    }
+                    let body = self.expand(&token, &arguments)?;
+                    for body_token in body.into_iter().rev() {
+                        tokens.push_front(body_token);
+                    }
+                }
+                _ => self.octo_statement(&token, &mut tokens)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn octo_statement(
+        &mut self,
+        token: &Token,
+        tokens: &mut VecDeque<Token>,
+    ) -> Result<(), AssembleError> {
+        let mut next = |what: &str| {
+            tokens
+                .pop_front()
+                .ok_or_else(|| token.error(format!("expected {} after `{}`", what, token.text)))
+        };
+        let expect = |found: Token, text: &str| -> Result<(), AssembleError> {
+            if found.text != text {
+                return Err(found.error(format!("expected `{}`, found `{}`", text, found.text)));
+            }
+            Ok(())
+        };
+
+        let instruction = match token.text.as_str() {
+            "clear" => Instruction::ClearDisplay,
+            "return" | ";" => Instruction::Return,
+            "scroll-right" => Instruction::ScrollRight,
+            "scroll-left" => Instruction::ScrollLeft,
+            "exit" => Instruction::Exit,
+            "lores" => Instruction::LowRes,
+            "hires" => Instruction::HighRes,
+            "audio" => Instruction::LoadAudio,
+            "scroll-down" => Instruction::ScrollDown(self.nibble(&next("a number")?)?),
+            "scroll-up" => Instruction::ScrollUp(self.nibble(&next("a number")?)?),
+            "plane" => Instruction::SelectPlanes(self.ranged(&next("a plane")?, 0, 3)? as u8),
+            "bcd" => Instruction::BCDRepresentation(register(&next("a register")?)?),
+            "saveflags" => Instruction::StoreFlags(register(&next("a register")?)?),
+            "loadflags" => Instruction::LoadFlags(register(&next("a register")?)?),
+            "jump" => return self.emit_address(&next("an address")?, Instruction::Jump),
+            "jump0" => return self.emit_address(&next("an address")?, Instruction::JumpPlusZero),
+            "save" | "load" => {
+                let x = register(&next("a register")?)?;
+                let range = tokens.front().map(|t| t.text == "-").unwrap_or(false);
+                match (token.text.as_str(), range) {
+                    ("save", false) => Instruction::StoreRegisters(x),
+                    (_, false) => Instruction::LoadRegisters(x),
+                    (name, true) => {
+                        tokens.pop_front();
+                        let y = match tokens.pop_front() {
+                            Some(y) => register(&y)?,
+                            None => return Err(token.error("expected a register after `-`")),
+                        };
+                        if name == "save" {
+                            Instruction::SaveRange(x, y)
+                        } else {
+                            Instruction::LoadRange(x, y)
+                        }
+                    }
+                }
+            }
+            "sprite" => {
+                let x = register(&next("a register")?)?;
+                let y = register(&next("a register")?)?;
+                match self.nibble(&next("a height")?)? {
+                    0 => Instruction::DrawLarge(x, y),
+                    n => Instruction::Draw(x, y, n),
+                }
+            }
+            "if" => {
+                // `if` runs the next statement when the condition holds so
+                // every condition assembles to the skip with the opposite test
+                let x = register(&next("a register")?)?;
+                let operator = next("a comparison")?;
+                let instruction = match operator.text.as_str() {
+                    "key" => Instruction::SkipIfNotPressed(x),
+                    "-key" => Instruction::SkipIfPressed(x),
+                    "==" | "!=" => {
+                        let operand = next("a value")?;
+                        let equals = operator.text == "==";
+                        match (parse_register(&operand), equals) {
+                            (Some(y), true) => Instruction::SkipIfNotEqual(x, y),
+                            (Some(y), false) => Instruction::SkipIfEqual(x, y),
+                            (None, true) => {
+                                Instruction::SkipIfNotEqualsByte(x, self.byte(&operand)?)
+                            }
+                            (None, false) => Instruction::SkipIfEqualsByte(x, self.byte(&operand)?),
+                        }
+                    }
+                    _ => {
+                        return Err(
+                            operator.error(format!("unsupported comparison `{}`", operator.text))
+                        )
+                    }
+                };
+                expect(next("`then`")?, "then")?;
+                instruction
+            }
+            "i" => {
+                let operator = next("an operator")?;
+                match operator.text.as_str() {
+                    "+=" => Instruction::AddToI(register(&next("a register")?)?),
+                    ":=" => {
+                        let operand = next("a value")?;
+                        match operand.text.as_str() {
+                            "hex" => Instruction::LoadSprite(register(&next("a register")?)?),
+                            "bighex" => {
+                                Instruction::LoadLargeSprite(register(&next("a register")?)?)
+                            }
+                            "long" => {
+                                return self
+                                    .emit_address(&next("an address")?, Instruction::LoadILong)
+                            }
+                            _ => return self.emit_address(&operand, Instruction::LoadI),
+                        }
+                    }
+                    _ => {
+                        return Err(operator
+                            .error(format!("unsupported operator `{}` for i", operator.text)))
+                    }
+                }
+            }
+            "delay" | "buzzer" | "pitch" => {
+                expect(next("`:=`")?, ":=")?;
+                let x = register(&next("a register")?)?;
+                match token.text.as_str() {
+                    "delay" => Instruction::SetDelayTimer(x),
+                    "buzzer" => Instruction::SetSoundTimer(x),
+                    _ => Instruction::SetPitch(x),
+                }
+            }
+            _ if parse_register(token).is_some() => {
+                let x = register(token)?;
+                let operator = next("an operator")?;
+                let operand = next("a value")?;
+                let y = parse_register(&operand);
+                match (operator.text.as_str(), y) {
+                    (":=", Some(y)) => Instruction::Move(x, y),
+                    (":=", None) => match operand.text.as_str() {
+                        "random" => Instruction::Random(x, self.byte(&next("a mask")?)?),
+                        "delay" => Instruction::LoadDelayTimer(x),
+                        "key" => Instruction::WaitForKeyPress(x),
+                        _ => Instruction::LoadByte(x, self.byte(&operand)?),
+                    },
+                    ("+=", Some(y)) => Instruction::Add(x, y),
+                    ("+=", None) => Instruction::AddByte(x, self.byte(&operand)?),
+                    ("-=", Some(y)) => Instruction::Sub(x, y),
+                    ("=-", Some(y)) => Instruction::ReverseSub(x, y),
+                    ("|=", Some(y)) => Instruction::Or(x, y),
+                    ("&=", Some(y)) => Instruction::And(x, y),
+                    ("^=", Some(y)) => Instruction::Xor(x, y),
+                    (">>=", Some(y)) => Instruction::ShiftRight(x, y),
+                    ("<<=", Some(y)) => Instruction::ShiftLeft(x, y),
+                    _ => {
+                        return Err(operator.error(format!(
+                            "unsupported operation `{} {} {}`",
+                            token.text, operator.text, operand.text
+                        )))
+                    }
+                }
+            }
+            // numbers (and constants) on their own are data bytes
+            _ if parse_number(token).is_some() || self.constants.contains_key(&token.text) => {
+                let value = self.byte(token)?;
+                self.output.push(value);
+                return Ok(());
+            }
+            // any other name calls the subroutine with that label
+            _ if is_identifier(&token.text) => {
+                return self.emit_address(token, Instruction::Call);
+            }
+            _ => return Err(token.error(format!("unexpected `{}`", token.text))),
+        };
+
+        self.emit(instruction);
+        Ok(())
+    }
+}
+
+// number of operands a Cowgod mnemonic takes for an instruction
+fn instruction_operands(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::ClearDisplay
+        | Instruction::Return
+        | Instruction::ScrollRight
+        | Instruction::ScrollLeft
+        | Instruction::Exit
+        | Instruction::LowRes
+        | Instruction::HighRes
+        | Instruction::LoadAudio => 0,
+        Instruction::ScrollDown(_)
+        | Instruction::ScrollUp(_)
+        | Instruction::SelectPlanes(_)
+        | Instruction::SetPitch(_)
+        | Instruction::SkipIfPressed(_)
+        | Instruction::SkipIfNotPressed(_) => 1,
+        Instruction::Draw(..) | Instruction::DrawLarge(..) => 3,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{disassemble_rom, mnemonic};
+    use std::collections::BTreeMap;
+
+    const SYNTAXES: [Syntax; 2] = [Syntax::Cowgod, Syntax::Octo];
+
+    // one of every instruction, with operands that use every bit of their field
+    fn every_instruction() -> Vec<Instruction> {
+        vec![
+            Instruction::ScrollDown(0xF),
+            Instruction::ScrollUp(0x1),
+            Instruction::ClearDisplay,
+            Instruction::Return,
+            Instruction::ScrollRight,
+            Instruction::ScrollLeft,
+            Instruction::Exit,
+            Instruction::LowRes,
+            Instruction::HighRes,
+            Instruction::Jump(0xFFF),
+            Instruction::Call(0x234),
+            Instruction::SkipIfEqualsByte(0x1, 0xFF),
+            Instruction::SkipIfNotEqualsByte(0xF, 0x00),
+            Instruction::SkipIfEqual(0x2, 0xE),
+            Instruction::SaveRange(0x3, 0xD),
+            Instruction::LoadRange(0xD, 0x3),
+            Instruction::LoadByte(0x4, 0x80),
+            Instruction::AddByte(0x5, 0x7F),
+            Instruction::Move(0x6, 0x7),
+            Instruction::Or(0x7, 0x8),
+            Instruction::And(0x8, 0x9),
+            Instruction::Xor(0x9, 0xA),
+            Instruction::Add(0xA, 0xB),
+            Instruction::Sub(0xB, 0xC),
+            Instruction::ShiftRight(0xC, 0xD),
+            Instruction::ReverseSub(0xD, 0xE),
+            Instruction::ShiftLeft(0xE, 0xF),
+            Instruction::SkipIfNotEqual(0xF, 0x0),
+            Instruction::LoadI(0xABC),
+            Instruction::JumpPlusZero(0x300),
+            Instruction::Random(0x0, 0x0F),
+            Instruction::Draw(0x1, 0x2, 0xF),
+            Instruction::DrawLarge(0x3, 0x4),
+            Instruction::SkipIfPressed(0x5),
+            Instruction::SkipIfNotPressed(0x6),
+            Instruction::LoadILong(0xFFFF),
+            Instruction::SelectPlanes(0x3),
+            Instruction::LoadAudio,
+            Instruction::LoadDelayTimer(0x7),
+            Instruction::WaitForKeyPress(0x8),
+            Instruction::SetDelayTimer(0x9),
+            Instruction::SetSoundTimer(0xA),
+            Instruction::AddToI(0xB),
+            Instruction::LoadSprite(0xC),
+            Instruction::LoadLargeSprite(0xD),
+            Instruction::BCDRepresentation(0xE),
+            Instruction::SetPitch(0xF),
+            Instruction::StoreRegisters(0x0),
+            Instruction::LoadRegisters(0x1),
+            Instruction::StoreFlags(0x2),
+            Instruction::LoadFlags(0x3),
+        ]
+    }
+
+    fn error(source: &str, syntax: Syntax) -> AssembleError {
+        assemble(source, syntax).expect_err("the source shouldn't assemble")
+    }
+
+    #[test]
+    fn encodes_and_decodes_every_instruction() {
+        for instruction in every_instruction() {
+            let bytes = instruction.encode();
+            assert_eq!(bytes.len(), instruction.size() as usize);
+            assert_eq!(Instruction::decode(&bytes), Some(instruction));
+        }
+    }
+
+    #[test]
+    fn assembles_every_mnemonic() {
+        for syntax in SYNTAXES.iter().copied() {
+            for instruction in every_instruction() {
+                let source = mnemonic(&instruction, syntax, &BTreeMap::new());
+                assert_eq!(
+                    assemble(&source, syntax),
+                    Ok(instruction.encode()),
+                    "{:?} from `{}` in {:?}",
+                    instruction,
+                    source,
+                    syntax
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reassembles_a_disassembled_rom() {
+        let mut rom: Vec<u8> = every_instruction()
+            .iter()
+            .flat_map(|instruction| instruction.encode())
+            .collect();
+        // a jump back into the program gets a label
+        rom.extend(Instruction::Jump(0x202).encode());
+        // and some data that isn't code
+        rom.extend([0x12, 0x34, 0x56]);
+
+        for syntax in SYNTAXES.iter().copied() {
+            let source = disassemble_rom(&rom).render(syntax);
+            assert_eq!(assemble(&source, syntax), Ok(rom.clone()), "{:?}", syntax);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_instructions() {
+        let error_at = error("CLS\nFROB V1", Syntax::Cowgod);
+        assert_eq!((error_at.line, error_at.column), (2, 1));
+        assert!(error_at.message.contains("unknown instruction `FROB`"));
+
+        let error_at = error("clear\nv1 ~= v2", Syntax::Octo);
+        assert_eq!((error_at.line, error_at.column), (2, 4));
+        assert!(error_at.message.contains("unsupported operation"));
+    }
+
+    #[test]
+    fn rejects_undefined_labels() {
+        let error_at = error("JP nowhere", Syntax::Cowgod);
+        assert_eq!((error_at.line, error_at.column), (1, 4));
+        assert!(error_at.message.contains("undefined label `nowhere`"));
+
+        let error_at = error(": main\n  jump nowhere", Syntax::Octo);
+        assert_eq!((error_at.line, error_at.column), (2, 8));
+        assert!(error_at.message.contains("undefined label `nowhere`"));
+    }
+
+    #[test]
+    fn rejects_immediates_out_of_range() {
+        let error_at = error("LD V1, 256", Syntax::Cowgod);
+        assert_eq!((error_at.line, error_at.column), (1, 8));
+        assert!(error_at.message.contains("out of range"));
+
+        let error_at = error("DRW V1, V2, 16", Syntax::Cowgod);
+        assert!(error_at.message.contains("out of range"));
+
+        let error_at = error("JP 0x1000", Syntax::Cowgod);
+        assert!(error_at.message.contains("doesn't fit in 12 bits"));
+
+        let error_at = error("v1 := 256", Syntax::Octo);
+        assert_eq!((error_at.line, error_at.column), (1, 7));
+        assert!(error_at.message.contains("out of range"));
+
+        let error_at = error("sprite v1 v2 16", Syntax::Octo);
+        assert!(error_at.message.contains("out of range"));
+    }
+}
@@ -0,0 +1,274 @@
+use std::f64::consts::TAU;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const WAV_SAMPLE_RATE: u32 = 44100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioSettings {
+    pub frequency: f64, // tone frequency in Hz
+    pub volume: f32,    // from 0.0 (muted) to 1.0
+    pub waveform: Waveform,
+}
+
+// what the beeper should be playing during a 60Hz frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sound {
+    pub active: bool,
+    pub pattern: Option<[u8; 16]>, // XO-CHIP audio pattern buffer
+    pub pitch: u8,                 // XO-CHIP pitch register
+}
+
+pub trait AudioSink {
+    // called once per 60Hz frame, right after the timers tick
+    fn frame(&mut self, sound: Sound);
+
+    // flush whatever the sink has buffered
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+impl Default for Sound {
+    fn default() -> Sound {
+        Sound {
+            active: false,
+            pattern: None,
+            pitch: 64,
+        }
+    }
+}
+
+// generates the beeper samples, shared by all sinks
+pub struct Oscillator {
+    settings: AudioSettings,
+    phase: f64, // position within the current period (0.0..1.0)
+}
+
+impl Oscillator {
+    pub fn new(settings: AudioSettings) -> Oscillator {
+        Oscillator {
+            settings,
+            phase: 0.0,
+        }
+    }
+
+    pub fn sample(&mut self, sound: &Sound, sample_rate: f64) -> f32 {
+        if !sound.active {
+            self.phase = 0.0;
+            return 0.0;
+        }
+
+        let (value, frequency) = match sound.pattern {
+            Some(pattern) => {
+                // the 128 bit pattern plays at 4000 * 2^((pitch - 64) / 48) bits per second
+                let rate = 4000.0 * 2f64.powf((sound.pitch as f64 - 64.0) / 48.0);
+                let bit = (self.phase * 128.0) as usize % 128;
+                let on = (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1;
+                (if on { 1.0 } else { -1.0 }, rate / 128.0)
+            }
+            None => {
+                let value = match self.settings.waveform {
+                    Waveform::Square => {
+                        if self.phase < 0.5 {
+                            1.0
+                        } else {
+                            -1.0
+                        }
+                    }
+                    Waveform::Sine => (self.phase * TAU).sin(),
+                    Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+                    Waveform::Sawtooth => 2.0 * self.phase - 1.0,
+                };
+                (value, self.settings.frequency)
+            }
+        };
+
+        self.phase = (self.phase + frequency / sample_rate).fract();
+        value as f32 * self.settings.volume
+    }
+}
+
+// plays nothing, for when there is no audio device
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn frame(&mut self, _sound: Sound) {}
+}
+
+// renders the beeper into a 16-bit mono WAV file (useful without a sound card)
+pub struct WavSink {
+    path: PathBuf,
+    oscillator: Oscillator,
+    samples: Vec<i16>,
+}
+
+impl WavSink {
+    pub fn new<P: AsRef<Path>>(path: P, settings: AudioSettings) -> WavSink {
+        WavSink {
+            path: path.as_ref().to_path_buf(),
+            oscillator: Oscillator::new(settings),
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl AudioSink for WavSink {
+    fn frame(&mut self, sound: Sound) {
+        let samples_per_frame = WAV_SAMPLE_RATE / 60;
+        for _ in 0..samples_per_frame {
+            let sample = self.oscillator.sample(&sound, WAV_SAMPLE_RATE as f64);
+            self.samples.push((sample * i16::MAX as f32) as i16);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        let data_size = self.samples.len() as u32 * 2;
+
+        // RIFF header followed by the format and data chunks
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + data_size).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?; // chunk size
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&1u16.to_le_bytes())?; // mono
+        file.write_all(&WAV_SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(&(WAV_SAMPLE_RATE * 2).to_le_bytes())?; // byte rate
+        file.write_all(&2u16.to_le_bytes())?; // block align
+        file.write_all(&16u16.to_le_bytes())?; // bits per sample
+        file.write_all(b"data")?;
+        file.write_all(&data_size.to_le_bytes())?;
+        for sample in &self.samples {
+            file.write_all(&sample.to_le_bytes())?;
+        }
+        file.flush()
+    }
+}
+
+// plays the beeper through the default output device
+#[cfg(feature = "system-audio")]
+pub struct SystemSink {
+    sound: std::sync::Arc<std::sync::Mutex<Sound>>,
+    _stream: cpal::Stream,
+}
+
+#[cfg(feature = "system-audio")]
+impl SystemSink {
+    pub fn new(settings: AudioSettings) -> Result<SystemSink, Box<dyn std::error::Error>> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use std::sync::{Arc, Mutex};
+
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or("no audio output device available")?;
+        let supported = device.default_output_config()?;
+        let format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+
+        let sound = Arc::new(Mutex::new(Sound::default()));
+        let stream = match format {
+            cpal::SampleFormat::F32 => {
+                Self::build_stream::<f32>(&device, &config, settings, sound.clone())?
+            }
+            cpal::SampleFormat::I16 => {
+                Self::build_stream::<i16>(&device, &config, settings, sound.clone())?
+            }
+            cpal::SampleFormat::U16 => {
+                Self::build_stream::<u16>(&device, &config, settings, sound.clone())?
+            }
+        };
+        stream.play()?;
+
+        Ok(SystemSink {
+            sound,
+            _stream: stream,
+        })
+    }
+
+    fn build_stream<T: cpal::Sample>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        settings: AudioSettings,
+        sound: std::sync::Arc<std::sync::Mutex<Sound>>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        use cpal::traits::DeviceTrait;
+
+        let sample_rate = config.sample_rate.0 as f64;
+        let channels = config.channels as usize;
+        let mut oscillator = Oscillator::new(settings);
+
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let sound = *sound.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    let value = T::from(&oscillator.sample(&sound, sample_rate));
+                    for sample in frame.iter_mut() {
+                        *sample = value;
+                    }
+                }
+            },
+            |e| eprintln!("audio error: {}", e),
+        )
+    }
+}
+
+#[cfg(feature = "system-audio")]
+impl AudioSink for SystemSink {
+    fn frame(&mut self, sound: Sound) {
+        *self.sound.lock().unwrap() = sound;
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Waveform, String> {
+        match value.to_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" | "saw" => Ok(Waveform::Sawtooth),
+            _ => Err(format!(
+                "unknown waveform `{}` (expected square, sine, triangle or sawtooth)",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Sawtooth => "sawtooth",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,423 @@
+use crate::gamepad::GamepadEvent;
+use piston_window::{Button, ControllerAxisEvent, Event, HatState, Key, PressEvent, ReleaseEvent};
+use rusty_chip8::palette::Palette;
+use serde::de::value::Error as ValueError;
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// the QWERTY 1234/QWER/ASDF/ZXCV layout
+const DEFAULT_KEYS: [(usize, &str); 16] = [
+    (0x1, "D1"),
+    (0x2, "D2"),
+    (0x3, "D3"),
+    (0xC, "D4"),
+    (0x4, "Q"),
+    (0x5, "W"),
+    (0x6, "E"),
+    (0xD, "R"),
+    (0x7, "A"),
+    (0x8, "S"),
+    (0x9, "D"),
+    (0xE, "F"),
+    (0xA, "Z"),
+    (0x0, "X"),
+    (0xB, "C"),
+    (0xF, "V"),
+];
+
+// the D-pad and left stick work like arrow keys on the 5/7/8/9 diamond most
+// games use, the first two buttons press 6 and 4
+const DEFAULT_CONTROLLER: [(usize, &str); 10] = [
+    (0x5, "HatUp"),
+    (0x8, "HatDown"),
+    (0x7, "HatLeft"),
+    (0x9, "HatRight"),
+    (0x5, "Axis1-"),
+    (0x8, "Axis1+"),
+    (0x7, "Axis0-"),
+    (0x9, "Axis0+"),
+    (0x6, "Button0"),
+    (0x4, "Button1"),
+];
+
+const DEFAULT_DEADZONE: f64 = 0.3;
+
+const DEFAULT_HOTKEYS: [(&str, &str); 10] = [
+    ("pause", "P"),
+    ("reset", "F12"),
+    ("rewind", "Backspace"),
+    ("frame_advance", "Period"),
+    ("speed_up", "Equals"),
+    ("speed_down", "Minus"),
+    ("fast_forward", "Backquote"),
+    ("rerecord", "Tab"),
+    ("palette", "F10"),
+    ("fullscreen", "F11"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Pause,
+    Reset,
+    Rewind,
+    FrameAdvance,
+    SpeedUp,
+    SpeedDown,
+    FastForward, // held down
+    Rerecord,
+    Palette, // switch to the next palette
+    Fullscreen,
+    SaveState(usize),
+    LoadState(usize),
+}
+
+// anything on the host that can be bound, from the keyboard or a controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    Key(Key),
+    Button(u8),
+    Hat(Direction),
+    Axis(u8, bool), // the axis pushed past the deadzone, positive or negative
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// an input, optionally with shift held (`Shift+F1`)
+#[derive(Debug, Clone, Copy)]
+struct Binding {
+    input: Input,
+    shift: bool,
+}
+
+// what a config file can contain, every entry replaces the bindings before it
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    keys: BTreeMap<String, Vec<String>>,
+    hotkeys: BTreeMap<String, Vec<String>>,
+    controller: ControllerConfig,
+    display: DisplayConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ControllerConfig {
+    deadzone: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DisplayConfig {
+    palette: Option<PaletteConfig>,
+}
+
+// the name of a built-in palette or a list of `#rrggbb` colors
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PaletteConfig {
+    Named(String),
+    Custom(Vec<String>),
+}
+
+pub struct Bindings {
+    keys: BTreeMap<usize, Vec<Binding>>,
+    hotkeys: Vec<(Hotkey, Vec<Binding>)>,
+    deadzone: f64,
+    palette: Palette, // the config files also pick the colors, not just the inputs
+}
+
+// turns window events into presses and releases of inputs, keeping track of
+// where the hats and sticks are so they can be released like keys
+pub struct InputTracker {
+    deadzone: f64,
+    hat: Vec<Direction>,
+    axes: BTreeMap<u8, Input>,
+}
+
+impl Bindings {
+    fn defaults() -> Bindings {
+        let mut keys: BTreeMap<usize, Vec<Binding>> = BTreeMap::new();
+        for (key, name) in DEFAULT_KEYS.iter().chain(DEFAULT_CONTROLLER.iter()) {
+            keys.entry(*key)
+                .or_default()
+                .push(parse_binding(name).unwrap());
+        }
+
+        // F1-F9 load a save state slot, Shift+F1-F9 save it
+        let mut hotkeys: Vec<(Hotkey, Vec<Binding>)> = DEFAULT_HOTKEYS
+            .iter()
+            .map(|(name, key)| {
+                (
+                    parse_hotkey(name).unwrap(),
+                    vec![parse_binding(key).unwrap()],
+                )
+            })
+            .collect();
+        for slot in 1..=9 {
+            let key = parse_binding(&format!("F{}", slot)).unwrap();
+            hotkeys.push((
+                Hotkey::SaveState(slot),
+                vec![Binding { shift: true, ..key }],
+            ));
+            hotkeys.push((Hotkey::LoadState(slot), vec![key]));
+        }
+
+        Bindings {
+            keys,
+            hotkeys,
+            deadzone: DEFAULT_DEADZONE,
+            palette: Palette::default(),
+        }
+    }
+
+    // the defaults, overridden by the user config and then by `<rom>.toml`
+    pub fn load(rom: &str) -> Result<Bindings, String> {
+        let mut bindings = Bindings::defaults();
+        let user = dirs::config_dir().map(|dir| dir.join("rusty-chip8").join("config.toml"));
+        let paths = user
+            .into_iter()
+            .chain(Some(PathBuf::from(format!("{}.toml", rom))));
+
+        for path in paths.filter(|path| path.exists()) {
+            bindings
+                .apply(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(bindings)
+    }
+
+    fn apply(&mut self, path: &Path) -> Result<(), String> {
+        let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let config: ConfigFile = toml::from_str(&source).map_err(|e| e.to_string())?;
+
+        for (name, keys) in &config.keys {
+            let key = usize::from_str_radix(name, 16)
+                .ok()
+                .filter(|key| *key < 16)
+                .ok_or_else(|| format!("unknown CHIP-8 key `{}` (keys go from 0 to F)", name))?;
+            self.keys.insert(key, parse_bindings(keys)?);
+        }
+        for (name, keys) in &config.hotkeys {
+            let hotkey = parse_hotkey(name).ok_or_else(|| format!("unknown hotkey `{}`", name))?;
+            let bindings = parse_bindings(keys)?;
+            match self.hotkeys.iter_mut().find(|(other, _)| *other == hotkey) {
+                Some((_, existing)) => *existing = bindings,
+                None => self.hotkeys.push((hotkey, bindings)),
+            }
+        }
+        if let Some(deadzone) = config.controller.deadzone {
+            if !(0.0..1.0).contains(&deadzone) {
+                return Err(format!(
+                    "the deadzone has to be between 0 and 1, not {}",
+                    deadzone
+                ));
+            }
+            self.deadzone = deadzone;
+        }
+        match config.display.palette {
+            Some(PaletteConfig::Named(name)) => {
+                self.palette =
+                    Palette::named(&name).ok_or_else(|| format!("unknown palette `{}`", name))?;
+            }
+            Some(PaletteConfig::Custom(colors)) => {
+                self.palette = Palette::from_hex("custom", &colors)?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn tracker(&self) -> InputTracker {
+        InputTracker {
+            deadzone: self.deadzone,
+            hat: Vec::new(),
+            axes: BTreeMap::new(),
+        }
+    }
+
+    // the CHIP-8 keys an input is bound to (shift doesn't matter for these)
+    pub fn chip8_keys(&self, input: Input) -> impl Iterator<Item = usize> + '_ {
+        self.keys
+            .iter()
+            .filter(move |(_, bindings)| bindings.iter().any(|b| b.input == input))
+            .map(|(chip8_key, _)| *chip8_key)
+    }
+
+    pub fn hotkey(&self, input: Input, shift: bool) -> Option<Hotkey> {
+        self.hotkeys
+            .iter()
+            .find(|(_, bindings)| {
+                bindings
+                    .iter()
+                    .any(|b| b.input == input && b.shift == shift)
+            })
+            .map(|(hotkey, _)| *hotkey)
+    }
+
+    // used for releases, which count whatever the modifiers are
+    pub fn is_bound(&self, hotkey: Hotkey, input: Input) -> bool {
+        self.hotkeys
+            .iter()
+            .any(|(other, bindings)| *other == hotkey && bindings.iter().any(|b| b.input == input))
+    }
+}
+
+impl InputTracker {
+    // every input that got pressed (true) or released (false) by an event
+    pub fn events(&mut self, event: &Event) -> Vec<(Input, bool)> {
+        let mut events = Vec::new();
+
+        match event.press_args() {
+            Some(Button::Keyboard(key)) => events.push((Input::Key(key), true)),
+            Some(Button::Controller(button)) => events.push((Input::Button(button.button), true)),
+            Some(Button::Hat(hat)) => self.move_hat(hat.state, &mut events),
+            _ => {}
+        }
+        match event.release_args() {
+            Some(Button::Keyboard(key)) => events.push((Input::Key(key), false)),
+            Some(Button::Controller(button)) => events.push((Input::Button(button.button), false)),
+            Some(Button::Hat(_)) => self.move_hat(HatState::Centered, &mut events),
+            _ => {}
+        }
+
+        if let Some(args) = event.controller_axis_args() {
+            self.move_axis(args.axis, args.position, &mut events);
+        }
+        events
+    }
+
+    // the same for the gamepads piston doesn't see
+    pub fn gamepad_events(&mut self, event: GamepadEvent) -> Vec<(Input, bool)> {
+        let mut events = Vec::new();
+        match event {
+            GamepadEvent::Button(button, pressed) => events.push((Input::Button(button), pressed)),
+            GamepadEvent::Axis(axis, position) => self.move_axis(axis, position, &mut events),
+            GamepadEvent::Hat(state) => self.move_hat(state, &mut events),
+        }
+        events
+    }
+
+    fn move_axis(&mut self, axis: u8, position: f64, events: &mut Vec<(Input, bool)>) {
+        let input = if position > self.deadzone {
+            Some(Input::Axis(axis, true))
+        } else if position < -self.deadzone {
+            Some(Input::Axis(axis, false))
+        } else {
+            None
+        };
+        let previous = match input {
+            Some(input) => self.axes.insert(axis, input),
+            None => self.axes.remove(&axis),
+        };
+        if previous != input {
+            events.extend(previous.map(|previous| (previous, false)));
+            events.extend(input.map(|input| (input, true)));
+        }
+    }
+
+    // diagonals hold two directions at once
+    fn move_hat(&mut self, state: HatState, events: &mut Vec<(Input, bool)>) {
+        let directions = match state {
+            HatState::Centered => vec![],
+            HatState::Up => vec![Direction::Up],
+            HatState::Down => vec![Direction::Down],
+            HatState::Left => vec![Direction::Left],
+            HatState::Right => vec![Direction::Right],
+            HatState::RightUp => vec![Direction::Right, Direction::Up],
+            HatState::RightDown => vec![Direction::Right, Direction::Down],
+            HatState::LeftUp => vec![Direction::Left, Direction::Up],
+            HatState::LeftDown => vec![Direction::Left, Direction::Down],
+        };
+        for released in self.hat.iter().filter(|d| !directions.contains(d)) {
+            events.push((Input::Hat(*released), false));
+        }
+        for pressed in directions.iter().filter(|d| !self.hat.contains(d)) {
+            events.push((Input::Hat(*pressed), true));
+        }
+        self.hat = directions;
+    }
+}
+
+fn parse_hotkey(name: &str) -> Option<Hotkey> {
+    let slot = |prefix: &str| {
+        name.strip_prefix(prefix)
+            .and_then(|slot| slot.parse().ok())
+            .filter(|slot| (1..=9).contains(slot))
+    };
+    match name {
+        "pause" => Some(Hotkey::Pause),
+        "reset" => Some(Hotkey::Reset),
+        "rewind" => Some(Hotkey::Rewind),
+        "frame_advance" => Some(Hotkey::FrameAdvance),
+        "speed_up" => Some(Hotkey::SpeedUp),
+        "speed_down" => Some(Hotkey::SpeedDown),
+        "fast_forward" => Some(Hotkey::FastForward),
+        "rerecord" => Some(Hotkey::Rerecord),
+        "palette" => Some(Hotkey::Palette),
+        "fullscreen" => Some(Hotkey::Fullscreen),
+        _ => slot("save_state_")
+            .map(Hotkey::SaveState)
+            .or_else(|| slot("load_state_").map(Hotkey::LoadState)),
+    }
+}
+
+fn parse_bindings(names: &[String]) -> Result<Vec<Binding>, String> {
+    names
+        .iter()
+        .map(|name| parse_binding(name).ok_or_else(|| format!("unknown key `{}`", name)))
+        .collect()
+}
+
+// key names are the ones piston uses: `A`, `D1`, `Up`, `Space`, `NumPad4`...
+// and controllers have `Button<n>`, `HatUp`/`HatDown`/`HatLeft`/`HatRight`
+// and `Axis<n>+`/`Axis<n>-`
+fn parse_binding(name: &str) -> Option<Binding> {
+    let (name, shift) = match name.strip_prefix("Shift+") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    Some(Binding {
+        input: parse_input(name)?,
+        shift,
+    })
+}
+
+fn parse_input(name: &str) -> Option<Input> {
+    if let Some(button) = name.strip_prefix("Button") {
+        return button.parse().ok().map(Input::Button);
+    }
+    if let Some(axis) = name.strip_prefix("Axis") {
+        let (axis, positive) = match (axis.strip_suffix('+'), axis.strip_suffix('-')) {
+            (Some(axis), _) => (axis, true),
+            (_, Some(axis)) => (axis, false),
+            _ => return None,
+        };
+        return axis.parse().ok().map(|axis| Input::Axis(axis, positive));
+    }
+    let direction = match name {
+        "HatUp" => Some(Direction::Up),
+        "HatDown" => Some(Direction::Down),
+        "HatLeft" => Some(Direction::Left),
+        "HatRight" => Some(Direction::Right),
+        _ => None,
+    };
+    if let Some(direction) = direction {
+        return Some(Input::Hat(direction));
+    }
+
+    let deserializer: serde::de::value::StrDeserializer<ValueError> = name.into_deserializer();
+    Key::deserialize(deserializer).ok().map(Input::Key)
+}
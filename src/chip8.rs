@@ -1,13 +1,31 @@
+use crate::audio::Sound;
 use crate::display::Display;
-use std::io::Write;
+use crate::error::Chip8Error;
+use crate::quirks::{IndexIncrement, Quirks, Timing};
+use crate::state::{SaveState, StateError, STATE_VERSION};
 
 use rand::prelude::*;
+use rand_pcg::Pcg32;
+use std::collections::VecDeque;
 
 pub type Address = u16;
 pub type Register = u8;
 
-pub const CPU_CLOCK: f64 = 600.0;
+// 600 instructions per second
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: f64 = 10.0;
+
+// the COSMAC VIP's 1802 runs at 1.76064MHz, 8 clock cycles per machine cycle,
+// which makes 3668 machine cycles per 60Hz frame
+pub const VIP_CYCLES_PER_FRAME: u32 = 3668;
+// the display interrupt routine and the DMA feeding the video chip take this
+// much of every frame, the interpreter gets the rest
+pub const VIP_INTERRUPT_CYCLES: u32 = 1832;
+pub const VIP_PROGRAM_CYCLES: u32 = VIP_CYCLES_PER_FRAME - VIP_INTERRUPT_CYCLES;
+// the interpreter's fetch and decode loop, paid by every instruction
+const VIP_FETCH_CYCLES: u32 = 68;
 pub const PROGRAM_MEMORY_OFFSET: usize = 0x200;
+pub const MEMORY_SIZE: usize = 0x1000;
+pub const EXTENDED_MEMORY_SIZE: usize = 0x10000;
 
 pub const FONT_MAP: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -28,15 +46,45 @@ pub const FONT_MAP: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
-#[derive(Debug)]
+// SUPER-CHIP 8x10 font, stored in memory right after the small font
+pub const BIG_FONT_OFFSET: usize = FONT_MAP.len();
+pub const BIG_FONT_MAP: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
+    ScrollDown(u8),                     // 00Cn
+    ScrollUp(u8),                       // 00Dn
     ClearDisplay,                       // 00E0
     Return,                             // 00EE
+    ScrollRight,                        // 00FB
+    ScrollLeft,                         // 00FC
+    Exit,                               // 00FD
+    LowRes,                             // 00FE
+    HighRes,                            // 00FF
     Jump(Address),                      // 1nnn
     Call(Address),                      // 2nnn
     SkipIfEqualsByte(Register, u8),     // 3xkk
     SkipIfNotEqualsByte(Register, u8),  // 4xkk
     SkipIfEqual(Register, Register),    // 5xy0
+    SaveRange(Register, Register),      // 5xy2
+    LoadRange(Register, Register),      // 5xy3
     LoadByte(Register, u8),             // 6xkk
     AddByte(Register, u8),              // 7xkk
     Move(Register, Register),           // 8xy0
@@ -45,80 +93,37 @@ pub enum Instruction {
     Xor(Register, Register),            // 8xy3
     Add(Register, Register),            // 8xy4
     Sub(Register, Register),            // 8xy5
-    ShiftRight(Register),               // 8xy6
+    ShiftRight(Register, Register),     // 8xy6
     ReverseSub(Register, Register),     // 8xy7
-    ShiftLeft(Register),                // 8xyE
+    ShiftLeft(Register, Register),      // 8xyE
     SkipIfNotEqual(Register, Register), // 9xy0
     LoadI(u16),                         // Annn
     JumpPlusZero(Address),              // Bnnn
     Random(Register, u8),               // Cxkk
     Draw(Register, Register, u8),       // Dxyn
+    DrawLarge(Register, Register),      // Dxy0
     SkipIfPressed(Register),            // Ex9E
     SkipIfNotPressed(Register),         // ExA1
+    LoadILong(Address),                 // F000 nnnn
+    SelectPlanes(u8),                   // Fn01
+    LoadAudio,                          // F002
     LoadDelayTimer(Register),           // Fx07
     WaitForKeyPress(Register),          // Fx0A
     SetDelayTimer(Register),            // Fx15
     SetSoundTimer(Register),            // Fx18
     AddToI(Register),                   // Fx1E
     LoadSprite(Register),               // Fx29
+    LoadLargeSprite(Register),          // Fx30
     BCDRepresentation(Register),        // Fx33
+    SetPitch(Register),                 // Fx3A
     StoreRegisters(Register),           // Fx55
     LoadRegisters(Register),            // Fx65
+    StoreFlags(Register),               // Fx75
+    LoadFlags(Register),                // Fx85
 }
 
-pub enum ProgramCounter {
-    Next,
-    Skip,
-    Jump(u16),
-}
-
-pub struct Chip8 {
-    pc: u16,          // program counter
-    v: [u8; 16],      // registers
-    i: u16,           // i register
-    sp: u8,           // stack pointer
-    stack: [u16; 16], // stack
-    v_delay: u8,      // delay register
-    v_sound: u8,      // sound register
-    memory: [u8; 4096],
-    keyboard: [bool; 16],
-    keyboard_wait_key: Option<u8>,
-    pub display: Display,
-}
-
-impl Chip8 {
-    pub fn new(data: Vec<u8>) -> Chip8 {
-        // create chip8 instance
-        let mut chip8 = Chip8 {
-            pc: 0x200,
-            v: [0; 16],
-            i: 0,
-            sp: 0,
-            stack: [0; 16],
-            v_delay: 0,
-            v_sound: 0,
-            memory: [0; 4096],
-            keyboard: [false; 16],
-            keyboard_wait_key: None,
-            display: Display::new(),
-        };
-
-        // load built-in fonts into memory
-        let mut buffer = &mut chip8.memory[0..FONT_MAP.len()];
-        buffer
-            .write(&FONT_MAP)
-            .expect("couldn't load built-in fonts into memory");
-
-        // load program into memory
-        let mut memory = &mut chip8.memory[PROGRAM_MEMORY_OFFSET..];
-        memory
-            .write(data.as_slice())
-            .expect("couldn't load program into memory");
-
-        chip8
-    }
-
-    pub fn to_instruction(&self, opcode: u16) -> Option<Instruction> {
+impl Instruction {
+    pub fn from_opcode(opcode: u16) -> Option<Instruction> {
         let nibbles = (
             (opcode >> 12) as u8,
             (opcode >> 8 & 0x0F) as u8,
@@ -134,16 +139,28 @@ impl Chip8 {
         let n = nibbles.3;
 
         match nibbles.0 {
-            0x0 => match n {
-                0x0 => Some(Instruction::ClearDisplay),
-                0xE => Some(Instruction::Return),
+            0x0 => match (x, y, n) {
+                (0x0, 0xC, n) => Some(Instruction::ScrollDown(n)),
+                (0x0, 0xD, n) => Some(Instruction::ScrollUp(n)),
+                (0x0, 0xE, 0x0) => Some(Instruction::ClearDisplay),
+                (0x0, 0xE, 0xE) => Some(Instruction::Return),
+                (0x0, 0xF, 0xB) => Some(Instruction::ScrollRight),
+                (0x0, 0xF, 0xC) => Some(Instruction::ScrollLeft),
+                (0x0, 0xF, 0xD) => Some(Instruction::Exit),
+                (0x0, 0xF, 0xE) => Some(Instruction::LowRes),
+                (0x0, 0xF, 0xF) => Some(Instruction::HighRes),
                 _ => None,
             },
             0x1 => Some(Instruction::Jump(nnn)),
             0x2 => Some(Instruction::Call(nnn)),
             0x3 => Some(Instruction::SkipIfEqualsByte(x, kk)),
             0x4 => Some(Instruction::SkipIfNotEqualsByte(x, kk)),
-            0x5 => Some(Instruction::SkipIfEqual(x, y)),
+            0x5 => match n {
+                0x0 => Some(Instruction::SkipIfEqual(x, y)),
+                0x2 => Some(Instruction::SaveRange(x, y)),
+                0x3 => Some(Instruction::LoadRange(x, y)),
+                _ => None,
+            },
             0x6 => Some(Instruction::LoadByte(x, kk)),
             0x7 => Some(Instruction::AddByte(x, kk)),
             0x8 => match n {
@@ -153,52 +170,364 @@ impl Chip8 {
                 0x3 => Some(Instruction::Xor(x, y)),
                 0x4 => Some(Instruction::Add(x, y)),
                 0x5 => Some(Instruction::Sub(x, y)),
-                0x6 => Some(Instruction::ShiftRight(x)),
+                0x6 => Some(Instruction::ShiftRight(x, y)),
                 0x7 => Some(Instruction::ReverseSub(x, y)),
-                0xE => Some(Instruction::ShiftLeft(x)),
+                0xE => Some(Instruction::ShiftLeft(x, y)),
                 _ => None,
             },
             0x9 => Some(Instruction::SkipIfNotEqual(x, y)),
             0xA => Some(Instruction::LoadI(nnn)),
             0xB => Some(Instruction::JumpPlusZero(nnn)),
             0xC => Some(Instruction::Random(x, kk)),
-            0xD => Some(Instruction::Draw(x, y, n)),
+            0xD => match n {
+                0x0 => Some(Instruction::DrawLarge(x, y)),
+                _ => Some(Instruction::Draw(x, y, n)),
+            },
             0xE => match kk {
                 0x9E => Some(Instruction::SkipIfPressed(x)),
                 0xA1 => Some(Instruction::SkipIfNotPressed(x)),
                 _ => None,
             },
             0xF => match kk {
+                0x01 => Some(Instruction::SelectPlanes(x)),
+                0x02 if x == 0 => Some(Instruction::LoadAudio),
                 0x07 => Some(Instruction::LoadDelayTimer(x)),
                 0x0A => Some(Instruction::WaitForKeyPress(x)),
                 0x15 => Some(Instruction::SetDelayTimer(x)),
                 0x18 => Some(Instruction::SetSoundTimer(x)),
                 0x1E => Some(Instruction::AddToI(x)),
                 0x29 => Some(Instruction::LoadSprite(x)),
+                0x30 => Some(Instruction::LoadLargeSprite(x)),
                 0x33 => Some(Instruction::BCDRepresentation(x)),
+                0x3A => Some(Instruction::SetPitch(x)),
                 0x55 => Some(Instruction::StoreRegisters(x)),
                 0x65 => Some(Instruction::LoadRegisters(x)),
+                0x75 => Some(Instruction::StoreFlags(x)),
+                0x85 => Some(Instruction::LoadFlags(x)),
                 _ => None,
             },
             _ => None,
         }
     }
 
-    pub fn run_instruction(&mut self, instruction: Instruction) -> ProgramCounter {
+    // decode the instruction at the start of `bytes`
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        let word = |offset: usize| -> Option<u16> {
+            Some((*bytes.get(offset)? as u16) << 8 | *bytes.get(offset + 1)? as u16)
+        };
+        let opcode = word(0)?;
+        // the only instruction that spans two words
+        if opcode == 0xF000 {
+            return Some(Instruction::LoadILong(word(2)?));
+        }
+        Instruction::from_opcode(opcode)
+    }
+
+    // the bytes the instruction assembles to, the inverse of `decode`
+    pub fn encode(&self) -> Vec<u8> {
+        let op = |high: u16, x: u8, y: u8, n: u8| {
+            high << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | (n as u16 & 0xF)
+        };
+        let op_kk = |high: u16, x: u8, kk: u8| high << 12 | (x as u16 & 0xF) << 8 | kk as u16;
+        let op_nnn = |high: u16, nnn: u16| high << 12 | (nnn & 0x0FFF);
+
+        let opcode = match *self {
+            Instruction::ScrollDown(n) => op(0x0, 0x0, 0xC, n),
+            Instruction::ScrollUp(n) => op(0x0, 0x0, 0xD, n),
+            Instruction::ClearDisplay => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump(nnn) => op_nnn(0x1, nnn),
+            Instruction::Call(nnn) => op_nnn(0x2, nnn),
+            Instruction::SkipIfEqualsByte(x, kk) => op_kk(0x3, x, kk),
+            Instruction::SkipIfNotEqualsByte(x, kk) => op_kk(0x4, x, kk),
+            Instruction::SkipIfEqual(x, y) => op(0x5, x, y, 0x0),
+            Instruction::SaveRange(x, y) => op(0x5, x, y, 0x2),
+            Instruction::LoadRange(x, y) => op(0x5, x, y, 0x3),
+            Instruction::LoadByte(x, kk) => op_kk(0x6, x, kk),
+            Instruction::AddByte(x, kk) => op_kk(0x7, x, kk),
+            Instruction::Move(x, y) => op(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => op(0x8, x, y, 0x1),
+            Instruction::And(x, y) => op(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => op(0x8, x, y, 0x3),
+            Instruction::Add(x, y) => op(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => op(0x8, x, y, 0x5),
+            Instruction::ShiftRight(x, y) => op(0x8, x, y, 0x6),
+            Instruction::ReverseSub(x, y) => op(0x8, x, y, 0x7),
+            Instruction::ShiftLeft(x, y) => op(0x8, x, y, 0xE),
+            Instruction::SkipIfNotEqual(x, y) => op(0x9, x, y, 0x0),
+            Instruction::LoadI(nnn) => op_nnn(0xA, nnn),
+            Instruction::JumpPlusZero(nnn) => op_nnn(0xB, nnn),
+            Instruction::Random(x, kk) => op_kk(0xC, x, kk),
+            Instruction::Draw(x, y, n) => op(0xD, x, y, n),
+            Instruction::DrawLarge(x, y) => op(0xD, x, y, 0x0),
+            Instruction::SkipIfPressed(x) => op_kk(0xE, x, 0x9E),
+            Instruction::SkipIfNotPressed(x) => op_kk(0xE, x, 0xA1),
+            Instruction::LoadILong(nnnn) => {
+                let [high, low] = nnnn.to_be_bytes();
+                return vec![0xF0, 0x00, high, low];
+            }
+            Instruction::SelectPlanes(n) => op_kk(0xF, n, 0x01),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::LoadDelayTimer(x) => op_kk(0xF, x, 0x07),
+            Instruction::WaitForKeyPress(x) => op_kk(0xF, x, 0x0A),
+            Instruction::SetDelayTimer(x) => op_kk(0xF, x, 0x15),
+            Instruction::SetSoundTimer(x) => op_kk(0xF, x, 0x18),
+            Instruction::AddToI(x) => op_kk(0xF, x, 0x1E),
+            Instruction::LoadSprite(x) => op_kk(0xF, x, 0x29),
+            Instruction::LoadLargeSprite(x) => op_kk(0xF, x, 0x30),
+            Instruction::BCDRepresentation(x) => op_kk(0xF, x, 0x33),
+            Instruction::SetPitch(x) => op_kk(0xF, x, 0x3A),
+            Instruction::StoreRegisters(x) => op_kk(0xF, x, 0x55),
+            Instruction::LoadRegisters(x) => op_kk(0xF, x, 0x65),
+            Instruction::StoreFlags(x) => op_kk(0xF, x, 0x75),
+            Instruction::LoadFlags(x) => op_kk(0xF, x, 0x85),
+        };
+        opcode.to_be_bytes().to_vec()
+    }
+
+    // size of the instruction in bytes
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+}
+
+pub enum ProgramCounter {
+    Next,
+    Skip,
+    Jump(u16),
+}
+
+// a running Fx0A, see `Chip8::key_wait`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyWait {
+    pub register: Register,  // where the key goes
+    pub pressed: Option<u8>, // the key held down, the wait ends when it's released
+}
+
+// instructions per frame have to be more than 0 (and finite, or a frame never ends)
+pub fn is_valid_speed(instructions: f64) -> bool {
+    instructions.is_finite() && instructions > 0.0
+}
+
+pub struct Chip8 {
+    pc: u16,           // program counter
+    v: [u8; 16],       // registers
+    i: u16,            // i register
+    sp: u8,            // stack pointer
+    stack: [u16; 16],  // stack
+    v_delay: u8,       // delay register
+    v_sound: u8,       // sound register
+    frame_cycles: u32, // VIP machine cycles run since the last display interrupt
+    cycle_budget: f64, // instructions (VIP machine cycles) left to run in this frame
+    in_frame: bool,    // between `begin_frame` and `end_frame`, only then is the budget used up
+    memory: Vec<u8>,
+    keyboard: [bool; 16],
+    keyboard_wait_key: Option<u8>, // the register Fx0A loads the key into
+    keyboard_wait_pressed: Option<u8>, // the key whose release finishes Fx0A (key release quirk)
+    vblank_wait: bool,             // a sprite was drawn, nothing runs until the next frame
+    rpl: [u8; 16],                 // SUPER-CHIP user flags
+    audio_pattern: Option<[u8; 16]>,
+    pitch: u8,
+    halted: bool,
+    quirks: Quirks,
+    instructions_per_frame: f64,
+    seed: u64,
+    rng: Pcg32,
+    random_log: Option<Vec<u8>>, // Cxkk values kept for a movie
+    random_replay: VecDeque<u8>, // Cxkk values a movie plays back
+    rom: Vec<u8>,
+    display: Display,
+}
+
+impl Chip8 {
+    pub fn new(data: Vec<u8>, quirks: Quirks) -> Result<Chip8, Chip8Error> {
+        let memory_size = if quirks.extended_memory {
+            EXTENDED_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        };
+
+        // a fresh seed every run, unless `set_seed` picks one
+        let seed = rand::random();
+
+        // create chip8 instance
+        let mut chip8 = Chip8 {
+            pc: PROGRAM_MEMORY_OFFSET as u16,
+            v: [0; 16],
+            i: 0,
+            sp: 0,
+            stack: [0; 16],
+            v_delay: 0,
+            v_sound: 0,
+            frame_cycles: 0,
+            cycle_budget: 0.0,
+            in_frame: false,
+            memory: vec![0; memory_size],
+            keyboard: [false; 16],
+            keyboard_wait_key: None,
+            keyboard_wait_pressed: None,
+            vblank_wait: false,
+            rpl: [0; 16],
+            audio_pattern: None,
+            pitch: 64,
+            halted: false,
+            quirks,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            seed,
+            rng: Pcg32::seed_from_u64(seed),
+            random_log: None,
+            random_replay: VecDeque::new(),
+            rom: Vec::new(),
+            display: Display::new(),
+        };
+
+        chip8.load_rom(&data)?;
+        Ok(chip8)
+    }
+
+    // power cycle the machine with a new program (the user flags survive)
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), Chip8Error> {
+        let max = self.memory.len() - PROGRAM_MEMORY_OFFSET;
+        if data.len() > max {
+            return Err(Chip8Error::RomTooLarge {
+                size: data.len(),
+                max,
+            });
+        }
+
+        self.pc = PROGRAM_MEMORY_OFFSET as u16;
+        self.v = [0; 16];
+        self.i = 0;
+        self.sp = 0;
+        self.stack = [0; 16];
+        self.v_delay = 0;
+        self.v_sound = 0;
+        self.frame_cycles = 0;
+        self.cycle_budget = 0.0;
+        self.keyboard = [false; 16];
+        self.keyboard_wait_key = None;
+        self.keyboard_wait_pressed = None;
+        self.vblank_wait = false;
+        self.audio_pattern = None;
+        self.pitch = 64;
+        self.halted = false;
+        self.rng = Pcg32::seed_from_u64(self.seed);
+        self.random_log = None;
+        self.random_replay.clear();
+        self.display = Display::new();
+        self.memory.iter_mut().for_each(|byte| *byte = 0);
+
+        // load built-in fonts into memory
+        self.memory[..FONT_MAP.len()].copy_from_slice(&FONT_MAP);
+        self.memory[BIG_FONT_OFFSET..BIG_FONT_OFFSET + BIG_FONT_MAP.len()]
+            .copy_from_slice(&BIG_FONT_MAP);
+
+        // load program into memory
+        self.memory[PROGRAM_MEMORY_OFFSET..PROGRAM_MEMORY_OFFSET + data.len()]
+            .copy_from_slice(data);
+        self.rom = data.to_vec();
+
+        Ok(())
+    }
+
+    // restart the currently loaded program
+    pub fn reset(&mut self) {
+        let rom = std::mem::take(&mut self.rom);
+        self.load_rom(&rom)
+            .expect("the loaded ROM always fits in memory");
+    }
+
+    pub fn read_word(&self, address: u16) -> u16 {
+        let len = self.memory.len();
+        let address = address as usize % len;
+        (self.memory[address] as u16) << 8 | self.memory[(address + 1) % len] as u16
+    }
+
+    pub fn decode(&self, address: u16) -> Option<Instruction> {
+        // enough bytes for the longest instruction, wrapping around the end of memory
+        let len = self.memory.len();
+        let bytes: Vec<u8> = (0..4)
+            .map(|offset| self.memory[(address as usize + offset) % len])
+            .collect();
+        Instruction::decode(&bytes)
+    }
+
+    // unknown opcodes are skipped like any other instruction
+    fn instruction_size(&self, address: u16) -> u16 {
+        self.decode(address)
+            .map_or(2, |instruction| instruction.size())
+    }
+
+    pub fn to_instruction(&self, opcode: u16) -> Option<Instruction> {
+        Instruction::from_opcode(opcode)
+    }
+
+    pub fn run_instruction(
+        &mut self,
+        instruction: Instruction,
+    ) -> Result<ProgramCounter, Chip8Error> {
         // run instruction and return next program counter
-        match instruction {
+        let next = match instruction {
+            Instruction::ScrollDown(n) => {
+                self.display.scroll_down(n as usize);
+                ProgramCounter::Next
+            }
+            Instruction::ScrollUp(n) => {
+                self.display.scroll_up(n as usize);
+                ProgramCounter::Next
+            }
             Instruction::ClearDisplay => {
                 self.display.clear();
                 ProgramCounter::Next
             }
             Instruction::Return => {
+                if self.sp == 0 {
+                    return Err(Chip8Error::StackUnderflow {
+                        pc: self.pc,
+                        opcode: self.read_word(self.pc),
+                    });
+                }
                 self.sp -= 1;
                 let addr = self.stack[self.sp as usize];
                 ProgramCounter::Jump(addr)
             }
+            Instruction::ScrollRight => {
+                self.display.scroll_right(4);
+                ProgramCounter::Next
+            }
+            Instruction::ScrollLeft => {
+                self.display.scroll_left(4);
+                ProgramCounter::Next
+            }
+            Instruction::Exit => {
+                self.halted = true;
+                ProgramCounter::Jump(self.pc)
+            }
+            Instruction::LowRes => {
+                self.display.set_hires(false);
+                ProgramCounter::Next
+            }
+            Instruction::HighRes => {
+                self.display.set_hires(true);
+                ProgramCounter::Next
+            }
             Instruction::Jump(addr) => ProgramCounter::Jump(addr),
             Instruction::Call(addr) => {
-                self.stack[self.sp as usize] = self.pc + 2;
+                if self.sp as usize == self.stack.len() {
+                    return Err(Chip8Error::StackOverflow {
+                        pc: self.pc,
+                        opcode: self.read_word(self.pc),
+                    });
+                }
+                self.stack[self.sp as usize] = self.wrap_address(self.pc.wrapping_add(2));
                 self.sp += 1;
                 ProgramCounter::Jump(addr)
             }
@@ -223,6 +552,21 @@ impl Chip8 {
                     ProgramCounter::Next
                 }
             }
+            Instruction::SaveRange(x, y) => {
+                let address = self.i as usize;
+                for (offset, register) in Self::register_range(x, y).enumerate() {
+                    self.write_memory(address + offset, self.read_register(register))?;
+                }
+                ProgramCounter::Next
+            }
+            Instruction::LoadRange(x, y) => {
+                let address = self.i as usize;
+                for (offset, register) in Self::register_range(x, y).enumerate() {
+                    let value = self.read_memory(address + offset, 1)?[0];
+                    self.load_register(register, value);
+                }
+                ProgramCounter::Next
+            }
             Instruction::LoadByte(x, value) => {
                 self.load_register(x, value);
                 ProgramCounter::Next
@@ -240,16 +584,25 @@ impl Chip8 {
             Instruction::Or(x, y) => {
                 let value = self.read_register(x) | self.read_register(y);
                 self.load_register(x, value);
+                if self.quirks.logic_resets_vf {
+                    self.load_register(0xF, 0);
+                }
                 ProgramCounter::Next
             }
             Instruction::And(x, y) => {
                 let value = self.read_register(x) & self.read_register(y);
                 self.load_register(x, value);
+                if self.quirks.logic_resets_vf {
+                    self.load_register(0xF, 0);
+                }
                 ProgramCounter::Next
             }
             Instruction::Xor(x, y) => {
                 let value = self.read_register(x) ^ self.read_register(y);
                 self.load_register(x, value);
+                if self.quirks.logic_resets_vf {
+                    self.load_register(0xF, 0);
+                }
                 ProgramCounter::Next
             }
             Instruction::Add(x, y) => {
@@ -265,10 +618,12 @@ impl Chip8 {
                 self.load_register(x, value);
                 ProgramCounter::Next
             }
-            Instruction::ShiftRight(x) => {
-                let value = self.read_register(x) >> 1;
-                self.load_register(0xF, self.read_register(x) & 0b1);
+            Instruction::ShiftRight(x, y) => {
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                let flag = self.read_register(source) & 0b1;
+                let value = self.read_register(source) >> 1;
                 self.load_register(x, value);
+                self.load_register(0xF, flag);
                 ProgramCounter::Next
             }
             Instruction::ReverseSub(x, y) => {
@@ -278,10 +633,12 @@ impl Chip8 {
                 self.load_register(x, value);
                 ProgramCounter::Next
             }
-            Instruction::ShiftLeft(x) => {
-                let value = self.read_register(x) << 1;
-                self.load_register(0xF, self.read_register(x) >> 7);
+            Instruction::ShiftLeft(x, y) => {
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                let flag = self.read_register(source) >> 7;
+                let value = self.read_register(source) << 1;
                 self.load_register(x, value);
+                self.load_register(0xF, flag);
                 ProgramCounter::Next
             }
             Instruction::SkipIfNotEqual(x, y) => {
@@ -292,42 +649,92 @@ impl Chip8 {
                 }
             }
             Instruction::LoadI(value) => {
-                self.i = value;
+                self.i = self.wrap_address(value);
+                ProgramCounter::Next
+            }
+            Instruction::LoadILong(value) => {
+                self.i = self.wrap_address(value);
+                ProgramCounter::Next
+            }
+            Instruction::SelectPlanes(planes) => {
+                self.display.select_planes(planes);
+                ProgramCounter::Next
+            }
+            Instruction::LoadAudio => {
+                let mut pattern = [0; 16];
+                pattern.copy_from_slice(self.read_memory(self.i as usize, 16)?);
+                self.audio_pattern = Some(pattern);
                 ProgramCounter::Next
             }
             Instruction::JumpPlusZero(address) => {
-                let address = address + self.read_register(0) as u16;
+                // the SCHIP interpreters read the register from the highest nibble
+                let register = if self.quirks.jump_uses_vx {
+                    (address >> 8) as u8
+                } else {
+                    0
+                };
+                let address = address + self.read_register(register) as u16;
                 ProgramCounter::Jump(address)
             }
             Instruction::Random(x, value) => {
-                let mut range = rand::thread_rng();
-                let random = range.gen_range(0..255) as u8;
+                // the generator moves on either way, so its state matches the recording's
+                let generated: u8 = self.rng.gen();
+                let random = self.random_replay.pop_front().unwrap_or(generated);
+                if let Some(log) = &mut self.random_log {
+                    log.push(random);
+                }
                 self.load_register(x, random & value);
                 ProgramCounter::Next
             }
             Instruction::Draw(x, y, n) => {
+                // XO-CHIP reads one sprite for every selected plane
+                let start = self.i as usize;
+                let length = n as usize * self.display.plane_count();
+                let sprite = self.read_memory(start, length)?.to_vec();
+
+                let x = self.read_register(x);
+                let y = self.read_register(y);
+
+                let collision = self.display.draw(x, y, &sprite, self.quirks.clip_sprites);
+
+                self.load_register(0xF, collision as u8);
+                self.vblank_wait = self.quirks.display_wait;
+
+                ProgramCounter::Next
+            }
+            Instruction::DrawLarge(_, _) if !self.quirks.large_sprites => {
+                // a sprite of 0 rows, which can't collide with anything
+                self.load_register(0xF, 0);
+                self.vblank_wait = self.quirks.display_wait;
+
+                ProgramCounter::Next
+            }
+            Instruction::DrawLarge(x, y) => {
                 let start = self.i as usize;
-                let end = start + n as usize;
-                let sprite = &self.memory[start..end];
+                let length = 32 * self.display.plane_count();
+                let sprite = self.read_memory(start, length)?.to_vec();
 
                 let x = self.read_register(x);
                 let y = self.read_register(y);
 
-                let collision = self.display.draw(x, y, sprite);
+                let collision = self
+                    .display
+                    .draw_large(x, y, &sprite, self.quirks.clip_sprites);
 
                 self.load_register(0xF, collision as u8);
+                self.vblank_wait = self.quirks.display_wait;
 
                 ProgramCounter::Next
             }
             Instruction::SkipIfPressed(x) => {
-                if self.keyboard[self.read_register(x) as usize] {
+                if self.keyboard[(self.read_register(x) & 0xF) as usize] {
                     ProgramCounter::Skip
                 } else {
                     ProgramCounter::Next
                 }
             }
             Instruction::SkipIfNotPressed(x) => {
-                if !self.keyboard[self.read_register(x) as usize] {
+                if !self.keyboard[(self.read_register(x) & 0xF) as usize] {
                     ProgramCounter::Skip
                 } else {
                     ProgramCounter::Next
@@ -337,9 +744,14 @@ impl Chip8 {
                 self.load_register(x, self.v_delay);
                 ProgramCounter::Next
             }
+            // the program counter stays on Fx0A until the wait is over
             Instruction::WaitForKeyPress(x) => {
                 self.keyboard_wait_key = Some(x);
-                ProgramCounter::Next
+                // the VIP sees a key that's already held down at once, and waits for its release
+                if self.quirks.key_release {
+                    self.keyboard_wait_pressed = (0..16).find(|&key| self.keyboard[key as usize]);
+                }
+                ProgramCounter::Jump(self.pc)
             }
             Instruction::SetDelayTimer(x) => {
                 let value = self.read_register(x);
@@ -352,45 +764,159 @@ impl Chip8 {
                 ProgramCounter::Next
             }
             Instruction::AddToI(x) => {
-                self.i += self.read_register(x) as u16;
+                self.i = self.wrap_address(self.i.wrapping_add(self.read_register(x) as u16));
                 ProgramCounter::Next
             }
             Instruction::LoadSprite(x) => {
-                let x = self.read_register(x);
-                self.i = (x * 5) as u16;
+                let x = self.read_register(x) & 0xF;
+                self.i = x as u16 * 5;
+                ProgramCounter::Next
+            }
+            Instruction::LoadLargeSprite(x) => {
+                let x = self.read_register(x) as usize;
+                self.i = (BIG_FONT_OFFSET + (x & 0xF) * 10) as u16;
                 ProgramCounter::Next
             }
             Instruction::BCDRepresentation(x) => {
                 let address = self.i as usize;
                 let value = self.read_register(x);
 
-                self.memory[address] = (value / 100) % 10;
-                self.memory[address + 1] = (value / 10) % 10;
-                self.memory[address + 2] = value % 10;
+                self.write_memory(address, (value / 100) % 10)?;
+                self.write_memory(address + 1, (value / 10) % 10)?;
+                self.write_memory(address + 2, value % 10)?;
 
                 ProgramCounter::Next
             }
+            Instruction::SetPitch(x) => {
+                self.pitch = self.read_register(x);
+                ProgramCounter::Next
+            }
             Instruction::StoreRegisters(x) => {
                 let limit = x as usize + 1;
                 let address = self.i as usize;
 
                 for offset in 0..limit {
-                    self.memory[address + offset] = self.read_register(offset as u8);
+                    self.write_memory(address + offset, self.read_register(offset as u8))?;
                 }
+                self.increment_index(x);
                 ProgramCounter::Next
             }
             Instruction::LoadRegisters(x) => {
                 let limit = x as usize + 1;
                 let address = self.i as usize;
 
-                for offset in 0..limit {
-                    self.load_register(offset as u8, self.memory[address + offset]);
-                }
+                let values = self.read_memory(address, limit)?.to_vec();
+                self.v[..limit].copy_from_slice(&values);
+                self.increment_index(x);
+                ProgramCounter::Next
+            }
+            Instruction::StoreFlags(x) => {
+                let limit = x as usize + 1;
+                self.rpl[..limit].copy_from_slice(&self.v[..limit]);
+                ProgramCounter::Next
+            }
+            Instruction::LoadFlags(x) => {
+                let limit = x as usize + 1;
+                self.v[..limit].copy_from_slice(&self.rpl[..limit]);
                 ProgramCounter::Next
             }
+        };
+        Ok(next)
+    }
+
+    fn read_memory(&self, address: usize, length: usize) -> Result<&[u8], Chip8Error> {
+        self.memory
+            .get(address..address + length)
+            .ok_or_else(|| self.out_of_bounds(address.max(self.memory.len())))
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), Chip8Error> {
+        match self.memory.get_mut(address) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(self.out_of_bounds(address)),
+        }
+    }
+
+    fn out_of_bounds(&self, address: usize) -> Chip8Error {
+        Chip8Error::MemoryOutOfBounds {
+            pc: self.pc,
+            opcode: self.read_word(self.pc),
+            address,
         }
     }
 
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = self.wrap_address(pc);
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, value: u16) {
+        self.i = self.wrap_address(value);
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    // the return addresses currently on the stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.v_delay
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.v_delay = value;
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.v_sound
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.v_sound = value;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut Display {
+        &mut self.display
+    }
+
+    pub fn is_key_pressed(&self, key: usize) -> bool {
+        self.keyboard.get(key).copied().unwrap_or(false)
+    }
+
     pub fn read_register(&self, idx: u8) -> u8 {
         self.v[idx as usize]
     }
@@ -399,19 +925,147 @@ impl Chip8 {
         self.v[idx as usize] = value;
     }
 
+    fn increment_index(&mut self, x: u8) {
+        match self.quirks.index_increment {
+            IndexIncrement::Unchanged => {}
+            IndexIncrement::ByX => self.i = self.i.wrapping_add(x as u16),
+            IndexIncrement::ByXPlusOne => self.i = self.i.wrapping_add(x as u16 + 1),
+        }
+        self.i = self.wrap_address(self.i);
+    }
+
+    // addresses past the end of memory wrap around to the start, so the
+    // program counter, I and the stack always point inside it
+    fn wrap_address(&self, address: u16) -> u16 {
+        (address as usize % self.memory.len()) as u16
+    }
+
+    // registers x through y, in reverse order when x > y
+    fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = u8>> {
+        if x <= y {
+            Box::new(x..=y)
+        } else {
+            Box::new((y..=x).rev())
+        }
+    }
+
+    pub fn sound(&self) -> Sound {
+        Sound {
+            active: self.v_sound > 0,
+            pattern: self.audio_pattern,
+            pitch: self.pitch,
+        }
+    }
+
+    pub fn rpl_flags(&self) -> [u8; 16] {
+        self.rpl
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; 16]) {
+        self.rpl = flags;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // restart the random number generator, the same seed gives the same numbers
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Pcg32::seed_from_u64(seed);
+    }
+
+    // keep every random byte Cxkk generates from now on, until the next power cycle
+    pub fn log_random(&mut self) {
+        self.random_log.get_or_insert_with(Vec::new);
+    }
+
+    // the random bytes logged since the last call
+    pub fn take_random_log(&mut self) -> Vec<u8> {
+        self.random_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Cxkk uses these bytes instead of the generator's until they run out, so a
+    // movie replays the same numbers whatever `rand` makes of the seed
+    pub fn replay_random(&mut self, values: &[u8]) {
+        self.random_replay = values.iter().copied().collect();
+    }
+
+    // the replayed random bytes not used yet, they're dropped
+    pub fn stop_random_replay(&mut self) -> usize {
+        let left = self.random_replay.len();
+        self.random_replay.clear();
+        left
+    }
+
+    // blocked on Fx0A until a key is pressed (or released, with the key release quirk)
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.keyboard_wait_key.is_some()
+    }
+
+    // what Fx0A is waiting for, `None` when it isn't
+    pub fn key_wait(&self) -> Option<KeyWait> {
+        self.keyboard_wait_key.map(|register| KeyWait {
+            register,
+            pressed: self.keyboard_wait_pressed,
+        })
+    }
+
+    // blocked after drawing until the timers tick (the display wait quirk)
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.vblank_wait
+    }
+
+    // nothing runs until a key press, the next frame, or ever again after exiting
+    pub fn is_blocked(&self) -> bool {
+        self.keyboard_wait_key.is_some() || self.vblank_wait || self.halted
+    }
+
+    // keys go from 0 to F, anything else is ignored
     pub fn handle_key_down(&mut self, key: usize) {
+        if key >= self.keyboard.len() {
+            return;
+        }
         self.keyboard[key] = true;
-        if let Some(x) = self.keyboard_wait_key {
-            self.load_register(x, key as u8);
-            self.keyboard_wait_key = None;
+        if self.keyboard_wait_key.is_none() {
+            return;
+        }
+        if !self.quirks.key_release {
+            self.finish_key_wait(key as u8);
+        } else if self.keyboard_wait_pressed.is_none() {
+            self.keyboard_wait_pressed = Some(key as u8);
         }
     }
 
     pub fn handle_key_up(&mut self, key: usize) {
+        if key >= self.keyboard.len() {
+            return;
+        }
         self.keyboard[key] = false;
+        if self.keyboard_wait_pressed == Some(key as u8) {
+            self.finish_key_wait(key as u8);
+        }
+    }
+
+    // load the key and move on past Fx0A
+    fn finish_key_wait(&mut self, key: u8) {
+        if let Some(x) = self.keyboard_wait_key.take() {
+            self.load_register(x, key);
+            self.pc = self.wrap_address(self.pc.wrapping_add(2));
+        }
+        self.keyboard_wait_pressed = None;
     }
 
+    // the 60Hz tick, which is also the vertical blank drawing waits for
     pub fn decrement_timers(&mut self) {
+        self.vblank_wait = false;
         if self.v_delay > 0 {
             self.v_delay -= 1;
         }
@@ -420,27 +1074,644 @@ impl Chip8 {
         }
     }
 
-    pub fn cycle(&mut self, delta: f64) {
-        // number of instructions to run in this cycle
-        let num_of_instructions = (CPU_CLOCK * delta).round() as usize;
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            version: STATE_VERSION,
+            pc: self.pc,
+            v: self.v,
+            i: self.i,
+            sp: self.sp,
+            stack: self.stack,
+            delay_timer: self.v_delay,
+            sound_timer: self.v_sound,
+            frame_cycles: self.frame_cycles,
+            cycle_budget: self.cycle_budget,
+            memory: self.memory.clone(),
+            keyboard: self.keyboard,
+            keyboard_wait_key: self.keyboard_wait_key,
+            keyboard_wait_pressed: self.keyboard_wait_pressed,
+            vblank_wait: self.vblank_wait,
+            rpl: self.rpl,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
+            halted: self.halted,
+            rng: self.rng.clone(),
+            display: self.display.buffer.to_vec(),
+            hires: self.display.is_hires(),
+            planes: self.display.selected_planes(),
+        }
+    }
+
+    // the machine is left untouched when the state doesn't fit it
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), StateError> {
+        if state.memory.len() != self.memory.len() {
+            return Err(StateError::MemorySize {
+                expected: self.memory.len(),
+                found: state.memory.len(),
+            });
+        }
+        if state.display.len() != self.display.buffer.len() || state.sp as usize > 16 {
+            return Err(StateError::Invalid("corrupted machine state".to_string()));
+        }
+        // register numbers and addresses that would index outside the machine
+        let keys = [state.keyboard_wait_key, state.keyboard_wait_pressed];
+        if keys.iter().flatten().any(|&index| index >= 16) {
+            return Err(StateError::Invalid(
+                "register or key out of range".to_string(),
+            ));
+        }
+        let registers = [state.pc, state.i];
+        let mut addresses = registers.iter().chain(state.stack.iter());
+        if addresses.any(|&address| address as usize >= self.memory.len()) {
+            return Err(StateError::Invalid("address out of memory".to_string()));
+        }
+        // a frame with more than that never runs out, one with less never runs
+        // anything (the longest instruction overdraws a frame, but by less than two)
+        let max_budget = match self.quirks.timing {
+            Timing::Fixed => self.instructions_per_frame + 1.0,
+            Timing::Vip => VIP_CYCLES_PER_FRAME as f64 + 1.0,
+        };
+        let budget = -2.0 * VIP_CYCLES_PER_FRAME as f64..=max_budget;
+        if state.frame_cycles >= VIP_PROGRAM_CYCLES || !budget.contains(&state.cycle_budget) {
+            return Err(StateError::Invalid("frame timing out of range".to_string()));
+        }
+
+        self.pc = state.pc;
+        self.v = state.v;
+        self.i = state.i;
+        self.sp = state.sp;
+        self.stack = state.stack;
+        self.v_delay = state.delay_timer;
+        self.v_sound = state.sound_timer;
+        self.frame_cycles = state.frame_cycles;
+        self.cycle_budget = state.cycle_budget;
+        self.memory.copy_from_slice(&state.memory);
+        self.keyboard = state.keyboard;
+        self.keyboard_wait_key = state.keyboard_wait_key;
+        self.keyboard_wait_pressed = state.keyboard_wait_pressed;
+        self.vblank_wait = state.vblank_wait;
+        self.rpl = state.rpl;
+        self.audio_pattern = state.audio_pattern;
+        self.pitch = state.pitch;
+        self.halted = state.halted;
+        self.rng = state.rng.clone();
+
+        self.display.set_hires(state.hires);
+        self.display.select_planes(state.planes);
+        self.display.buffer.copy_from_slice(&state.display);
+        self.display.mark_dirty();
+        Ok(())
+    }
+
+    // run a single instruction, does nothing while blocked
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        if self.is_blocked() {
+            return Ok(());
+        }
+
+        let instruction = self.decode(self.pc).ok_or(Chip8Error::UnknownOpcode {
+            pc: self.pc,
+            opcode: self.read_word(self.pc),
+        })?;
+        let size = instruction.size();
+        let cycles = self.vip_cycles(instruction);
+        let next = self.run_instruction(instruction)?;
+
+        match self.quirks.timing {
+            Timing::Fixed if self.in_frame => self.cycle_budget -= 1.0,
+            Timing::Fixed => {}
+            Timing::Vip => {
+                // taking a skip costs the VIP a few more cycles
+                let skip = matches!(next, ProgramCounter::Skip) as u32 * 4;
+                self.spend_cycles(cycles + skip);
+            }
+        }
+
+        let pc = match next {
+            ProgramCounter::Next => self.pc.wrapping_add(size),
+            ProgramCounter::Skip => {
+                // skipping has to step over a whole (possibly long) instruction
+                let next = self.wrap_address(self.pc.wrapping_add(size));
+                next.wrapping_add(self.instruction_size(next))
+            }
+            ProgramCounter::Jump(address) => address,
+        };
+        self.pc = self.wrap_address(pc);
+        Ok(())
+    }
+
+    // run up to `cycles` instructions outside of any frame, they don't count
+    // against the frame `run_frame` runs next
+    //
+    // a display wait ends a frame early, the timers tick and the program goes on
+    pub fn run(&mut self, cycles: usize) -> Result<(), Chip8Error> {
+        for _ in 0..cycles {
+            // there's no next frame to wait for a sprite, so it comes right away
+            if self.vblank_wait {
+                match self.quirks.timing {
+                    Timing::Fixed => self.decrement_timers(),
+                    Timing::Vip => {
+                        self.spend_cycles(VIP_PROGRAM_CYCLES.saturating_sub(self.frame_cycles))
+                    }
+                }
+            }
+            // block execution until a key is pressed (or forever after exiting)
+            if self.is_blocked() {
+                break;
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // one 60Hz frame: a frame's worth of instructions, then the timers tick
+    // once, returns how many instructions ran
+    //
+    // frontends call this 60 times a second, a `Scheduler` tells when
+    pub fn run_frame(&mut self) -> Result<usize, Chip8Error> {
+        self.begin_frame();
+        let mut count = 0;
+        while self.has_cycle_time() {
+            if self.is_blocked() {
+                self.idle();
+            } else {
+                self.step()?;
+                count += 1;
+            }
+        }
+        self.end_frame();
+        Ok(count)
+    }
+
+    // `run_frame` in parts, for frontends that stop in the middle of a frame:
+    // `begin_frame`, then `step` (or `idle` while blocked) as long as
+    // `has_cycle_time`, then `end_frame`
+    pub fn begin_frame(&mut self) {
+        self.in_frame = true;
+        // whatever didn't fit into the last frame carries over
+        self.cycle_budget += match self.quirks.timing {
+            Timing::Fixed => self.instructions_per_frame,
+            Timing::Vip => VIP_CYCLES_PER_FRAME as f64,
+        };
+    }
+
+    pub fn has_cycle_time(&self) -> bool {
+        self.cycle_budget >= 1.0
+    }
+
+    // nothing can run for the rest of the frame, with VIP timing the display
+    // interrupt still has to come so the timers keep counting down
+    pub fn idle(&mut self) {
+        match self.quirks.timing {
+            Timing::Fixed => self.cycle_budget = self.cycle_budget.fract(),
+            Timing::Vip => self.spend_cycles(VIP_PROGRAM_CYCLES.saturating_sub(self.frame_cycles)),
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.in_frame = false;
+        // with VIP timing the display interrupt already took care of them
+        if self.quirks.timing == Timing::Fixed {
+            self.decrement_timers();
+        }
+    }
+
+    // the speed with fixed timing, fractions are carried from frame to frame
+    pub fn instructions_per_frame(&self) -> f64 {
+        self.instructions_per_frame
+    }
+
+    // no instruction would ever run at 0, see `is_valid_speed`
+    pub fn set_instructions_per_frame(&mut self, instructions: f64) -> Result<(), Chip8Error> {
+        if !is_valid_speed(instructions) {
+            return Err(Chip8Error::InvalidSpeed { instructions });
+        }
+        self.instructions_per_frame = instructions;
+        Ok(())
+    }
+
+    fn spend_cycles(&mut self, cycles: u32) {
+        self.frame_cycles += cycles;
+        let mut spent = cycles as f64;
+        // long instructions like 00E0 can run through a whole interrupt
+        while self.frame_cycles >= VIP_PROGRAM_CYCLES {
+            self.frame_cycles -= VIP_PROGRAM_CYCLES;
+            spent += VIP_INTERRUPT_CYCLES as f64;
+            self.decrement_timers();
+        }
+        if self.in_frame {
+            self.cycle_budget -= spent;
+        }
+    }
+
+    // what an instruction costs on the VIP in machine cycles, not counting the
+    // extra cycles of a taken skip
+    //
+    // after Laurence Scotford's analysis of the VIP interpreter, the instructions
+    // the VIP didn't have are charged like a simple one
+    fn vip_cycles(&self, instruction: Instruction) -> u32 {
+        let cycles = match instruction {
+            Instruction::ClearDisplay => 3078,
+            Instruction::Return => 10,
+            Instruction::Jump(_) => 12,
+            Instruction::Call(_) => 26,
+            Instruction::SkipIfEqualsByte(_, _) | Instruction::SkipIfNotEqualsByte(_, _) => 10,
+            Instruction::SkipIfEqual(_, _) | Instruction::SkipIfNotEqual(_, _) => 14,
+            Instruction::LoadByte(_, _) => 6,
+            Instruction::AddByte(_, _) => 10,
+            Instruction::Move(_, _) => 12,
+            Instruction::Or(_, _)
+            | Instruction::And(_, _)
+            | Instruction::Xor(_, _)
+            | Instruction::Add(_, _)
+            | Instruction::Sub(_, _)
+            | Instruction::ShiftRight(_, _)
+            | Instruction::ReverseSub(_, _)
+            | Instruction::ShiftLeft(_, _) => 44,
+            Instruction::LoadI(_) => 12,
+            Instruction::JumpPlusZero(address) => {
+                // crossing into another page takes a little longer
+                let target = address.wrapping_add(self.read_register(0) as u16);
+                if target & 0xFF00 != address & 0xFF00 {
+                    24
+                } else {
+                    22
+                }
+            }
+            Instruction::Random(_, _) => 36,
+            Instruction::Draw(x, y, n) => {
+                // every row gets shifted into place one bit at a time, and spills
+                // into a second byte unless it starts on a byte boundary
+                let shift = self.read_register(x) as u32 % 8;
+                let top = self.read_register(y) as usize % self.display.height();
+                let rows = if self.quirks.clip_sprites {
+                    (n as usize).min(self.display.height() - top)
+                } else {
+                    n as usize
+                };
+                let per_row = if shift == 0 { 34 } else { 46 + 4 * shift };
+                26 + rows as u32 * per_row
+            }
+            Instruction::SkipIfPressed(_) | Instruction::SkipIfNotPressed(_) => 14,
+            Instruction::LoadDelayTimer(_)
+            | Instruction::SetDelayTimer(_)
+            | Instruction::SetSoundTimer(_) => 10,
+            Instruction::WaitForKeyPress(_) => 10,
+            Instruction::AddToI(_) => 16,
+            Instruction::LoadSprite(_) => 16,
+            Instruction::BCDRepresentation(x) => {
+                // the digits are counted out by repeated subtraction
+                let value = self.read_register(x);
+                let digits = (value / 100 + value / 10 % 10 + value % 10) as u32;
+                80 + 16 * digits
+            }
+            Instruction::StoreRegisters(x) | Instruction::LoadRegisters(x) => {
+                14 + 14 * (x as u32 + 1)
+            }
+            _ => 12,
+        };
+        VIP_FETCH_CYCLES + cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Preset;
+
+    const PRESETS: [Preset; 5] = [
+        Preset::Vip,
+        Preset::Chip48,
+        Preset::Schip,
+        Preset::XoChip,
+        Preset::Modern,
+    ];
+
+    fn chip8(preset: Preset, program: &[u8]) -> Chip8 {
+        Chip8::new(program.to_vec(), Quirks::new(preset)).unwrap()
+    }
+
+    // run the first `steps` instructions of `program`
+    fn run(preset: Preset, program: &[u8], steps: usize) -> Chip8 {
+        let mut chip8 = chip8(preset, program);
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn shift_quirk() {
+        // V1 = 03, V2 = 81, V1 >>= V2
+        let program = [0x61, 0x03, 0x62, 0x81, 0x81, 0x26];
+        for preset in PRESETS.iter().copied() {
+            let chip8 = run(preset, &program, 3);
+            let expected = match preset {
+                Preset::Vip | Preset::XoChip => 0x40,
+                Preset::Chip48 | Preset::Schip | Preset::Modern => 0x01,
+            };
+            assert_eq!(chip8.read_register(1), expected, "{:?}", preset);
+            assert_eq!(chip8.read_register(0xF), 1, "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn load_store_quirk() {
+        // I = 300, save V0 - V2, load V0 - V2
+        let program = [0xA3, 0x00, 0xF2, 0x55, 0xF2, 0x65];
+        for preset in PRESETS.iter().copied() {
+            let increment = match preset {
+                Preset::Vip | Preset::XoChip => 3,
+                Preset::Chip48 => 2,
+                Preset::Schip | Preset::Modern => 0,
+            };
+            let chip8 = run(preset, &program, 2);
+            assert_eq!(chip8.i(), 0x300 + increment, "{:?}", preset);
+            let chip8 = run(preset, &program, 3);
+            assert_eq!(chip8.i(), 0x300 + increment * 2, "{:?}", preset);
+        }
+    }
 
-        for _ in 0..num_of_instructions {
-            // block execution until a key is pressed
-            if self.keyboard_wait_key != None {
-                return;
+    #[test]
+    fn vf_reset_quirk() {
+        // VF = 05, V0 = 01, V1 = 02, then OR, AND and XOR
+        let setup = [0x6F, 0x05, 0x60, 0x01, 0x61, 0x02];
+        for operation in [0x80, 0x11, 0x80, 0x12, 0x80, 0x13].chunks(2) {
+            let program = [&setup[..], operation].concat();
+            for preset in PRESETS.iter().copied() {
+                let chip8 = run(preset, &program, 4);
+                let expected = match preset {
+                    Preset::Vip => 0,
+                    _ => 5,
+                };
+                assert_eq!(chip8.read_register(0xF), expected, "{:?}", preset);
             }
+        }
+    }
+
+    #[test]
+    fn jump_quirk() {
+        // V0 = 01, V2 = 04, jump to 210 + V0 or V2
+        let program = [0x60, 0x01, 0x62, 0x04, 0xB2, 0x10];
+        for preset in PRESETS.iter().copied() {
+            let chip8 = run(preset, &program, 3);
+            let expected = match preset {
+                Preset::Chip48 | Preset::Schip => 0x214,
+                Preset::Vip | Preset::XoChip | Preset::Modern => 0x211,
+            };
+            assert_eq!(chip8.pc(), expected, "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn clipping_quirk() {
+        // an 8x2 sprite in the bottom right corner, V0 = 60, V1 = 31
+        let program = [
+            0x60, 0x3C, 0x61, 0x1F, 0xA2, 0x0A, 0xD0, 0x12, 0x00, 0x00, 0xFF, 0xFF,
+        ];
+        for preset in PRESETS.iter().copied() {
+            let chip8 = run(preset, &program, 4);
+            let display = chip8.display();
+            assert_ne!(display.pixel(63, 31), 0, "{:?}", preset);
+            let wrapped = match preset {
+                Preset::XoChip | Preset::Modern => true,
+                Preset::Vip | Preset::Chip48 | Preset::Schip => false,
+            };
+            // the part past the right edge, the bottom edge and both
+            assert_eq!(display.pixel(0, 31) != 0, wrapped, "{:?}", preset);
+            assert_eq!(display.pixel(60, 0) != 0, wrapped, "{:?}", preset);
+            assert_eq!(display.pixel(0, 0) != 0, wrapped, "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn display_wait_quirk() {
+        // two sprites one after the other
+        let program = [0xA2, 0x06, 0xD0, 0x01, 0xD0, 0x01, 0x80];
+        for preset in PRESETS.iter().copied() {
+            let mut chip8 = chip8(preset, &program);
+            chip8.set_instructions_per_frame(3.0).unwrap();
+            chip8.run_frame().unwrap();
+            let expected = match preset {
+                Preset::Vip => 0x204,
+                _ => 0x206,
+            };
+            assert_eq!(chip8.pc(), expected, "{:?}", preset);
+            // the wait is over on the next frame
+            assert!(!chip8.is_blocked(), "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn key_release_quirk() {
+        // V1 = key
+        let program = [0xF1, 0x0A];
+        for preset in PRESETS.iter().copied() {
+            let mut chip8 = run(preset, &program, 1);
+            assert!(chip8.is_waiting_for_key(), "{:?}", preset);
+            chip8.handle_key_down(5);
+            let on_release = match preset {
+                Preset::Vip | Preset::XoChip => true,
+                Preset::Chip48 | Preset::Schip | Preset::Modern => false,
+            };
+            assert_eq!(chip8.is_waiting_for_key(), on_release, "{:?}", preset);
+            chip8.handle_key_up(5);
+            assert!(!chip8.is_waiting_for_key(), "{:?}", preset);
+            assert_eq!(chip8.read_register(1), 5, "{:?}", preset);
+            assert_eq!(chip8.pc(), 0x202, "{:?}", preset);
+        }
+    }
 
-            let pc = self.pc as usize;
-            let opcode: u16 = (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16;
+    #[test]
+    fn run_leaves_the_next_frame_alone() {
+        // jump to itself forever
+        let program = [0x12, 0x00];
+        for timing in [Timing::Fixed, Timing::Vip].iter().copied() {
+            let quirks = Quirks {
+                timing,
+                ..Quirks::new(Preset::Modern)
+            };
+            let mut fresh = Chip8::new(program.to_vec(), quirks).unwrap();
+            let per_frame = fresh.run_frame().unwrap();
 
-            let instruction = self.to_instruction(opcode).unwrap();
-            let next = self.run_instruction(instruction);
+            let mut chip8 = Chip8::new(program.to_vec(), quirks).unwrap();
+            chip8.run(600).unwrap();
+            assert_eq!(chip8.run_frame().unwrap(), per_frame, "{:?}", timing);
+            chip8.run(5).unwrap();
+            assert_eq!(chip8.run_frame().unwrap(), per_frame, "{:?}", timing);
+        }
+    }
 
-            match next {
-                ProgramCounter::Next => self.pc += 2,
-                ProgramCounter::Skip => self.pc += 4,
-                ProgramCounter::Jump(address) => self.pc = address,
+    #[test]
+    fn run_goes_past_the_display_wait() {
+        // two sprites, then jump to itself forever
+        let program = [0xA2, 0x08, 0xD0, 0x01, 0xD0, 0x01, 0x12, 0x06, 0x80];
+        for timing in [Timing::Fixed, Timing::Vip].iter().copied() {
+            for preset in PRESETS.iter().copied() {
+                let quirks = Quirks {
+                    timing,
+                    ..Quirks::new(preset)
+                };
+                let mut chip8 = Chip8::new(program.to_vec(), quirks).unwrap();
+                chip8.set_delay_timer(10);
+                chip8.run(600).unwrap();
+                assert_eq!(chip8.pc(), 0x206, "{:?} {:?}", preset, timing);
+                assert_eq!(chip8.display().pixel(0, 0), 0, "{:?} {:?}", preset, timing);
+                assert!(!chip8.is_blocked(), "{:?} {:?}", preset, timing);
             }
         }
+        // each wait was a frame for the timers
+        let mut chip8 = chip8(Preset::Vip, &program);
+        chip8.set_delay_timer(10);
+        chip8.run(4).unwrap();
+        assert_eq!(chip8.delay_timer(), 8);
+    }
+
+    #[test]
+    fn ignores_keys_out_of_range() {
+        // V1 = key
+        let mut chip8 = run(Preset::Modern, &[0xF1, 0x0A], 1);
+        chip8.handle_key_down(99);
+        chip8.handle_key_up(99);
+        assert!(!chip8.is_key_pressed(99));
+        assert!(chip8.is_waiting_for_key());
+    }
+
+    #[test]
+    fn large_sprite_quirk() {
+        // VF = 05, I = the font, draw V0, V0, 0
+        let program = [0x6F, 0x05, 0xA0, 0x00, 0xD0, 0x00];
+        for preset in PRESETS.iter().copied() {
+            let chip8 = run(preset, &program, 3);
+            let drawn = match preset {
+                Preset::Schip | Preset::XoChip | Preset::Modern => true,
+                Preset::Vip | Preset::Chip48 => false,
+            };
+            assert_eq!(chip8.display().pixel(0, 0) != 0, drawn, "{:?}", preset);
+            assert_eq!(chip8.read_register(0xF), 0, "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_speeds() {
+        let mut chip8 = chip8(Preset::Modern, &[]);
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY].iter().copied() {
+            assert!(matches!(
+                chip8.set_instructions_per_frame(speed),
+                Err(Chip8Error::InvalidSpeed { .. })
+            ));
+        }
+        assert_eq!(
+            chip8.instructions_per_frame(),
+            DEFAULT_INSTRUCTIONS_PER_FRAME
+        );
+        chip8.set_instructions_per_frame(0.5).unwrap();
+        assert_eq!(chip8.instructions_per_frame(), 0.5);
+    }
+
+    fn vip_timed(program: &[u8]) -> Chip8 {
+        let quirks = Quirks {
+            timing: Timing::Vip,
+            ..Quirks::new(Preset::Vip)
+        };
+        Chip8::new(program.to_vec(), quirks).unwrap()
+    }
+
+    // machine cycles the next instruction takes
+    fn vip_cost(chip8: &mut Chip8) -> u32 {
+        let before = chip8.frame_cycles;
+        chip8.step().unwrap();
+        chip8.frame_cycles - before
+    }
+
+    #[test]
+    fn vip_instruction_cost() {
+        // V0 = 05, I = 300, V0 += V1
+        let mut chip8 = vip_timed(&[0x60, 0x05, 0xA3, 0x00, 0x80, 0x14]);
+        assert_eq!(vip_cost(&mut chip8), VIP_FETCH_CYCLES + 6);
+        assert_eq!(vip_cost(&mut chip8), VIP_FETCH_CYCLES + 12);
+        assert_eq!(vip_cost(&mut chip8), VIP_FETCH_CYCLES + 44);
+
+        // and the frame's budget pays for it
+        let mut chip8 = vip_timed(&[0x60, 0x05]);
+        chip8.begin_frame();
+        chip8.step().unwrap();
+        assert_eq!(
+            chip8.cycle_budget,
+            (VIP_CYCLES_PER_FRAME - VIP_FETCH_CYCLES - 6) as f64
+        );
+    }
+
+    #[test]
+    fn vip_draw_cost() {
+        // I = the font, V1 = 00, a 5 row sprite at V0, V1
+        let draw = |x: u8| {
+            let mut chip8 = vip_timed(&[0xA0, 0x00, 0x60, x, 0x61, 0x00, 0xD0, 0x15]);
+            chip8.run(3).unwrap();
+            vip_cost(&mut chip8)
+        };
+        // a row on a byte boundary is written as it is
+        assert_eq!(draw(8), VIP_FETCH_CYCLES + 26 + 5 * 34);
+        // anything else is shifted bit by bit and spills into the next byte
+        assert_eq!(draw(9), VIP_FETCH_CYCLES + 26 + 5 * (46 + 4));
+        assert_eq!(draw(15), VIP_FETCH_CYCLES + 26 + 5 * (46 + 4 * 7));
+    }
+
+    #[test]
+    fn vip_skip_penalty() {
+        // skip if V0 == 00 (taken), then skip if V0 == 01 (not taken)
+        let mut chip8 = vip_timed(&[0x30, 0x00, 0x00, 0x00, 0x30, 0x01]);
+        assert_eq!(vip_cost(&mut chip8), VIP_FETCH_CYCLES + 10 + 4);
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(vip_cost(&mut chip8), VIP_FETCH_CYCLES + 10);
+    }
+
+    #[test]
+    fn vip_clear_runs_through_the_interrupt() {
+        let mut chip8 = vip_timed(&[0x00, 0xE0]);
+        chip8.set_delay_timer(10);
+        chip8.begin_frame();
+        chip8.step().unwrap();
+
+        let cost = VIP_FETCH_CYCLES + 3078;
+        assert!(cost > VIP_PROGRAM_CYCLES);
+        // the interrupt came in the middle and the timers ticked in it
+        assert_eq!(chip8.frame_cycles, cost - VIP_PROGRAM_CYCLES);
+        assert_eq!(chip8.delay_timer(), 9);
+        assert_eq!(
+            chip8.cycle_budget,
+            VIP_CYCLES_PER_FRAME as f64 - cost as f64 - VIP_INTERRUPT_CYCLES as f64
+        );
+        // and not again at the end of the frame
+        chip8.end_frame();
+        assert_eq!(chip8.delay_timer(), 9);
+    }
+
+    #[test]
+    fn vip_timers_tick_once_a_frame() {
+        // jump to itself forever
+        let mut chip8 = vip_timed(&[0x12, 0x00]);
+        chip8.set_delay_timer(30);
+        for frame in 1..=20 {
+            chip8.run_frame().unwrap();
+            assert_eq!(chip8.delay_timer(), 30 - frame, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn decodes_long_instructions_from_memory() {
+        // I = long 1234, then skip over another long load
+        let program = [0xF0, 0x00, 0x12, 0x34, 0x30, 0x00, 0xF0, 0x00, 0x56, 0x78];
+        let mut chip8 = chip8(Preset::XoChip, &program);
+        assert_eq!(chip8.decode(0x200), Some(Instruction::LoadILong(0x1234)));
+        chip8.run(2).unwrap();
+        assert_eq!(chip8.i(), 0x1234);
+        assert_eq!(chip8.pc(), 0x20A);
+
+        // the second word wraps around to the start of memory
+        let last = (EXTENDED_MEMORY_SIZE - 2) as u16;
+        chip8.memory_mut()[last as usize..].copy_from_slice(&[0xF0, 0x00]);
+        chip8.memory_mut()[..2].copy_from_slice(&[0xAB, 0xCD]);
+        assert_eq!(chip8.decode(last), Some(Instruction::LoadILong(0xABCD)));
     }
 }
@@ -0,0 +1,620 @@
+use crate::chip8::{Chip8, Instruction};
+use crate::error::Chip8Error;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// stops before an instruction touches memory in `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub end: usize,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// stops when the comparison becomes true, not on every instruction it holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub target: Target,
+    pub comparison: Comparison,
+    pub value: u16,
+    held: bool,
+}
+
+// why the debugger paused the machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    Watchpoint { address: usize, access: Access },
+    Condition(Condition),
+    Fault(Chip8Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Running,
+    Paused,
+    Step(usize),
+    StepOver(u8), // until the stack pointer is back to this depth
+    StepOut(u8),  // until the stack pointer drops below this depth
+}
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    conditions: Vec<Condition>,
+    mode: Mode,
+    resumed: bool,  // the instruction we stopped on runs without being checked again
+    in_frame: bool, // a stop cut the current frame short
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Condition {
+    pub fn new(target: Target, comparison: Comparison, value: u16) -> Condition {
+        Condition {
+            target,
+            comparison,
+            value,
+            held: false,
+        }
+    }
+
+    fn holds(&self, chip8: &Chip8) -> bool {
+        let current = match self.target {
+            Target::V(x) => chip8.read_register(x) as u16,
+            Target::I => chip8.i(),
+            Target::Pc => chip8.pc(),
+            Target::Sp => chip8.sp() as u16,
+            Target::DelayTimer => chip8.delay_timer() as u16,
+            Target::SoundTimer => chip8.sound_timer() as u16,
+        };
+        match self.comparison {
+            Comparison::Equal => current == self.value,
+            Comparison::NotEqual => current != self.value,
+            Comparison::Less => current < self.value,
+            Comparison::LessOrEqual => current <= self.value,
+            Comparison::Greater => current > self.value,
+            Comparison::GreaterOrEqual => current >= self.value,
+        }
+    }
+}
+
+impl Watchpoint {
+    fn hit(&self, access: Access, range: &Range<usize>) -> Option<usize> {
+        let watched = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        let start = range.start.max(self.start);
+        if watched && start < range.end.min(self.end) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            conditions: Vec::new(),
+            mode: Mode::Running,
+            resumed: false,
+            in_frame: false,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    pub fn resume(&mut self) {
+        self.start(Mode::Running);
+    }
+
+    pub fn step(&mut self, count: usize) {
+        self.start(Mode::Step(count.max(1)));
+    }
+
+    // like a step, but runs a whole subroutine when the next instruction calls one
+    pub fn step_over(&mut self, chip8: &Chip8) {
+        match chip8.decode(chip8.pc()) {
+            Some(Instruction::Call(_)) => self.start(Mode::StepOver(chip8.sp())),
+            _ => self.step(1),
+        }
+    }
+
+    // run until the current subroutine returns
+    pub fn step_out(&mut self, chip8: &Chip8) -> Result<(), String> {
+        if chip8.sp() == 0 {
+            return Err("not inside a subroutine".to_string());
+        }
+        self.start(Mode::StepOut(chip8.sp()));
+        Ok(())
+    }
+
+    fn start(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.resumed = true;
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    pub fn add_condition(&mut self, chip8: &Chip8, mut condition: Condition) {
+        // a condition that already holds only stops once it changes and comes back
+        condition.held = condition.holds(chip8);
+        self.conditions.push(condition);
+    }
+
+    pub fn remove_condition(&mut self, index: usize) -> Option<Condition> {
+        (index < self.conditions.len()).then(|| self.conditions.remove(index))
+    }
+
+    // the debugger's version of `Chip8::run_frame`, a frame cut short by a stop
+    // carries on where it left off the next time
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Option<StopReason> {
+        if !self.in_frame {
+            chip8.begin_frame();
+            self.in_frame = true;
+        }
+        while chip8.has_cycle_time() {
+            if self.mode == Mode::Paused {
+                return None;
+            }
+            if chip8.is_blocked() {
+                chip8.idle();
+                continue;
+            }
+            if let Some(reason) = self.run_instruction(chip8) {
+                self.mode = Mode::Paused;
+                return Some(reason);
+            }
+        }
+        chip8.end_frame();
+        self.in_frame = false;
+        None
+    }
+
+    fn run_instruction(&mut self, chip8: &mut Chip8) -> Option<StopReason> {
+        let pc = chip8.pc();
+
+        if !std::mem::take(&mut self.resumed) {
+            if self.breakpoints.contains(&pc) {
+                return Some(StopReason::Breakpoint(pc));
+            }
+            if let Some((access, range)) = chip8.decode(pc).and_then(|i| memory_access(chip8, i)) {
+                let hit = self.watchpoints.iter().find_map(|w| w.hit(access, &range));
+                if let Some(address) = hit {
+                    return Some(StopReason::Watchpoint { address, access });
+                }
+            }
+        }
+
+        // faults leave the program counter on the offending instruction
+        if let Err(e) = chip8.step() {
+            return Some(StopReason::Fault(e));
+        }
+
+        let mut reason = None;
+        for condition in self.conditions.iter_mut() {
+            let holds = condition.holds(chip8);
+            if holds && !condition.held && reason.is_none() {
+                reason = Some(StopReason::Condition(*condition));
+            }
+            condition.held = holds;
+        }
+        if reason.is_some() {
+            return reason;
+        }
+
+        match self.mode {
+            Mode::Step(1) => Some(StopReason::Step),
+            Mode::Step(count) => {
+                self.mode = Mode::Step(count - 1);
+                None
+            }
+            Mode::StepOver(sp) if chip8.sp() <= sp => Some(StopReason::Step),
+            Mode::StepOut(sp) if chip8.sp() < sp => Some(StopReason::Step),
+            _ => None,
+        }
+    }
+
+    // run a console command, returns what it printed
+    pub fn execute(&mut self, chip8: &mut Chip8, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(String::new()),
+        };
+        let arg = |index: usize| -> Result<u16, String> {
+            args.get(index)
+                .ok_or_else(|| format!("`{}` is missing an argument", command))
+                .and_then(|text| parse_number(text))
+        };
+
+        match *command {
+            "c" | "continue" => self.resume(),
+            "p" | "pause" => self.pause(),
+            "s" | "step" => self.step(if args.is_empty() { 1 } else { arg(0)? as usize }),
+            "n" | "next" => self.step_over(chip8),
+            "o" | "out" => self.step_out(chip8)?,
+            "b" | "break" => self.add_breakpoint(arg(0)?),
+            "d" | "delete" => {
+                if !self.remove_breakpoint(arg(0)?) {
+                    return Err(format!("no breakpoint at {}", args[0]));
+                }
+            }
+            "w" | "watch" => {
+                let (start, end) = parse_range(args.first().copied().unwrap_or(""))?;
+                let (read, write) = match args.get(1).copied().unwrap_or("rw") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    kind => return Err(format!("unknown access `{}` (expected r, w or rw)", kind)),
+                };
+                self.add_watchpoint(Watchpoint {
+                    start,
+                    end,
+                    read,
+                    write,
+                });
+            }
+            "unwatch" => {
+                self.remove_watchpoint(arg(0)? as usize)
+                    .ok_or("no such watchpoint")?;
+            }
+            "if" => {
+                let condition = parse_condition(args)?;
+                self.add_condition(chip8, condition);
+            }
+            "unif" => {
+                self.remove_condition(arg(0)? as usize)
+                    .ok_or("no such condition")?;
+            }
+            "l" | "list" => return Ok(self.list()),
+            "r" | "regs" => return Ok(registers(chip8)),
+            "m" | "mem" => {
+                let length = if args.len() > 1 { arg(1)? } else { 0x40 };
+                return Ok(dump_memory(chip8, arg(0)? as usize, length as usize));
+            }
+            "x" | "dis" => {
+                let address = if args.is_empty() { chip8.pc() } else { arg(0)? };
+                let count = if args.len() > 1 { arg(1)? } else { 8 };
+                return Ok(listing(chip8, address, count as usize));
+            }
+            "set" => {
+                let target = args
+                    .first()
+                    .ok_or_else(|| "`set` is missing a register".to_string())
+                    .and_then(|text| parse_target(text))?;
+                let value = arg(1)?;
+                match target {
+                    Target::V(x) => chip8.load_register(x, value as u8),
+                    Target::I => chip8.set_i(value),
+                    Target::Pc => chip8.set_pc(value),
+                    Target::DelayTimer => chip8.set_delay_timer(value as u8),
+                    Target::SoundTimer => chip8.set_sound_timer(value as u8),
+                    Target::Sp => return Err("the stack pointer can't be set".to_string()),
+                }
+            }
+            "poke" => {
+                let address = arg(0)? as usize;
+                let value = arg(1)? as u8;
+                let byte = chip8
+                    .memory_mut()
+                    .get_mut(address)
+                    .ok_or("address is out of memory")?;
+                *byte = value;
+            }
+            "h" | "help" => return Ok(HELP.trim_start().to_string()),
+            _ => return Err(format!("unknown command `{}` (try `help`)", command)),
+        }
+        Ok(String::new())
+    }
+
+    fn list(&self) -> String {
+        let mut output = String::new();
+        for address in &self.breakpoints {
+            output += &format!("break {:04X}\n", address);
+        }
+        for (index, w) in self.watchpoints.iter().enumerate() {
+            let kind = match (w.read, w.write) {
+                (true, true) => "rw",
+                (true, false) => "r",
+                _ => "w",
+            };
+            output += &format!("watch #{} {:04X}..{:04X} {}\n", index, w.start, w.end, kind);
+        }
+        for (index, condition) in self.conditions.iter().enumerate() {
+            output += &format!("if #{} {}\n", index, condition);
+        }
+        output
+    }
+
+    // the instruction the machine is about to run
+    pub fn location(chip8: &Chip8) -> String {
+        listing(chip8, chip8.pc(), 1)
+    }
+}
+
+const HELP: &str = "
+numbers are hexadecimal
+  c, continue           run until something stops the machine
+  p, pause              stop the machine
+  s, step [n]           run n instructions (1 by default)
+  n, next               step over subroutine calls
+  o, out                run until the current subroutine returns
+  b, break <addr>       stop when the program counter gets to addr
+  d, delete <addr>      remove a breakpoint
+  w, watch <addr>[..end] [r|w|rw]
+                        stop before an instruction reads or writes memory
+  unwatch <n>           remove watchpoint #n
+  if <reg> <op> <value> stop when the comparison becomes true
+                        reg is v0-vf, i, pc, sp, dt or st, op is == != < <= > >=
+  unif <n>              remove condition #n
+  l, list               list breakpoints, watchpoints and conditions
+  r, regs               show the registers
+  m, mem <addr> [len]   dump memory
+  x, dis [addr] [n]     disassemble n instructions (from pc by default)
+  set <reg> <value>     change a register
+  poke <addr> <value>   change a byte of memory
+";
+
+// the memory an instruction is going to touch
+fn memory_access(chip8: &Chip8, instruction: Instruction) -> Option<(Access, Range<usize>)> {
+    let i = chip8.i() as usize;
+    let planes = chip8.display().plane_count();
+    let (access, length) = match instruction {
+        Instruction::SaveRange(x, y) => (Access::Write, x.abs_diff(y) as usize + 1),
+        Instruction::LoadRange(x, y) => (Access::Read, x.abs_diff(y) as usize + 1),
+        Instruction::LoadAudio => (Access::Read, 16),
+        Instruction::Draw(_, _, n) => (Access::Read, n as usize * planes),
+        Instruction::DrawLarge(..) => (Access::Read, 32 * planes),
+        Instruction::BCDRepresentation(_) => (Access::Write, 3),
+        Instruction::StoreRegisters(x) => (Access::Write, x as usize + 1),
+        Instruction::LoadRegisters(x) => (Access::Read, x as usize + 1),
+        _ => return None,
+    };
+    Some((access, i..i + length))
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid number `{}`", text))
+}
+
+// `addr` watches a single byte, `start..end` a range
+fn parse_range(text: &str) -> Result<(usize, usize), String> {
+    match text.split_once("..") {
+        Some((start, end)) => {
+            let (start, end) = (parse_number(start)? as usize, parse_number(end)? as usize);
+            if start >= end {
+                return Err(format!("empty range `{}`", text));
+            }
+            Ok((start, end))
+        }
+        None => {
+            let start = parse_number(text)? as usize;
+            Ok((start, start + 1))
+        }
+    }
+}
+
+fn parse_target(text: &str) -> Result<Target, String> {
+    let target = match text.to_lowercase().as_str() {
+        "i" => Target::I,
+        "pc" => Target::Pc,
+        "sp" => Target::Sp,
+        "dt" => Target::DelayTimer,
+        "st" => Target::SoundTimer,
+        register => register
+            .strip_prefix('v')
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+            .map(Target::V)
+            .ok_or_else(|| format!("unknown register `{}`", text))?,
+    };
+    Ok(target)
+}
+
+fn parse_condition(args: &[&str]) -> Result<Condition, String> {
+    if args.len() != 3 {
+        return Err("expected `if <reg> <op> <value>`".to_string());
+    }
+    let comparison = match args[1] {
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        op => return Err(format!("unknown comparison `{}`", op)),
+    };
+    Ok(Condition::new(
+        parse_target(args[0])?,
+        comparison,
+        parse_number(args[2])?,
+    ))
+}
+
+fn registers(chip8: &Chip8) -> String {
+    let mut output = format!(
+        "pc {:04X}  i {:04X}  sp {}  dt {:02X}  st {:02X}\n",
+        chip8.pc(),
+        chip8.i(),
+        chip8.sp(),
+        chip8.delay_timer(),
+        chip8.sound_timer()
+    );
+    for (x, value) in chip8.registers().iter().enumerate() {
+        output += &format!("v{:X} {:02X}", x, value);
+        output.push(if x % 8 == 7 { '\n' } else { ' ' });
+    }
+    output += &format!("seed {}\n", chip8.seed());
+    if !chip8.stack().is_empty() {
+        let stack: Vec<String> = chip8.stack().iter().map(|a| format!("{:04X}", a)).collect();
+        output += &format!("stack {}\n", stack.join(" "));
+    }
+    output
+}
+
+fn dump_memory(chip8: &Chip8, address: usize, length: usize) -> String {
+    let memory = chip8.memory();
+    let end = (address + length).min(memory.len());
+    let mut output = String::new();
+    for start in (address.min(end)..end).step_by(16) {
+        let bytes: Vec<String> = memory[start..end.min(start + 16)]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        output += &format!("{:04X}  {}\n", start, bytes.join(" "));
+    }
+    output
+}
+
+fn listing(chip8: &Chip8, mut address: u16, count: usize) -> String {
+    let mut output = String::new();
+    for _ in 0..count {
+        let marker = if address == chip8.pc() { '>' } else { ' ' };
+        let (text, size) = match chip8.decode(address) {
+            Some(instruction) => (instruction.to_string(), instruction.size()),
+            None => ("???".to_string(), 2),
+        };
+        let raw: String = (0..size)
+            .map(|offset| {
+                format!(
+                    "{:02X}",
+                    chip8.memory()[(address as usize + offset as usize) % chip8.memory().len()]
+                )
+            })
+            .collect();
+        output += &format!("{} {:04X}  {:<8}  {}\n", marker, address, raw, text);
+        address = address.wrapping_add(size);
+    }
+    output
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let target = match self.target {
+            Target::V(x) => format!("v{:X}", x),
+            Target::I => "i".to_string(),
+            Target::Pc => "pc".to_string(),
+            Target::Sp => "sp".to_string(),
+            Target::DelayTimer => "dt".to_string(),
+            Target::SoundTimer => "st".to_string(),
+        };
+        let comparison = match self.comparison {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        };
+        write!(f, "{} {} {:X}", target, comparison, self.value)
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "stepped"),
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at {:04X}", address),
+            StopReason::Watchpoint { address, access } => {
+                let verb = match access {
+                    Access::Read => "read",
+                    Access::Write => "written",
+                };
+                write!(f, "watchpoint: {:04X} is about to be {}", address, verb)
+            }
+            StopReason::Condition(condition) => write!(f, "condition `{}` became true", condition),
+            StopReason::Fault(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Preset, Quirks};
+
+    #[test]
+    fn lists_the_end_of_the_address_space() {
+        for preset in [Preset::Modern, Preset::XoChip] {
+            let mut chip8 = Chip8::new(vec![0xF0, 0x00], Quirks::new(preset)).unwrap();
+            let mut debugger = Debugger::new();
+            for command in ["x ffff", "x fffe 4", "m ffff 10"] {
+                assert!(debugger.execute(&mut chip8, command).is_ok(), "{}", command);
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use crate::chip8::{Instruction, PROGRAM_MEMORY_OFFSET};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Syntax {
+    // mnemonics from Cowgod's technical reference
+    #[default]
+    Cowgod,
+    // statements of the Octo assembly language
+    Octo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineKind {
+    Code(Instruction),
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub kind: LineKind,
+}
+
+pub struct Disassembly {
+    pub lines: Vec<Line>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+// most data bytes shown on a single line
+const DATA_PER_LINE: usize = 8;
+
+// disassemble a program loaded at `origin`, following jumps and calls from the
+// entry point to tell code from data
+pub fn disassemble(rom: &[u8], origin: u16) -> Disassembly {
+    let end = origin as usize + rom.len();
+    let contains = |address: u16| (origin as usize..end).contains(&(address as usize));
+    let decode = |address: u16| Instruction::decode(&rom[(address - origin) as usize..]);
+
+    let mut code = BTreeMap::new();
+    let mut labels = BTreeMap::new();
+    let mut pending = vec![origin];
+
+    while let Some(address) = pending.pop() {
+        if !contains(address) || code.contains_key(&address) {
+            continue;
+        }
+        let instruction = match decode(address) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        code.insert(address, instruction);
+
+        let next = address.wrapping_add(instruction.size());
+        match instruction {
+            Instruction::Jump(target) => {
+                labels
+                    .entry(target)
+                    .or_insert_with(|| label("label", target));
+                pending.push(target);
+            }
+            Instruction::Call(target) => {
+                labels.insert(target, label("sub", target));
+                pending.push(target);
+                pending.push(next);
+            }
+            // the target depends on a register so the path can't be followed
+            Instruction::Return | Instruction::Exit | Instruction::JumpPlusZero(_) => {}
+            Instruction::SkipIfEqualsByte(..)
+            | Instruction::SkipIfNotEqualsByte(..)
+            | Instruction::SkipIfEqual(..)
+            | Instruction::SkipIfNotEqual(..)
+            | Instruction::SkipIfPressed(_)
+            | Instruction::SkipIfNotPressed(_) => {
+                pending.push(next);
+                if let Some(skipped) = contains(next).then(|| decode(next)).flatten() {
+                    pending.push(next.wrapping_add(skipped.size()));
+                }
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    // labels only make sense for addresses that start a line
+    let starts: BTreeSet<u16> = code.keys().copied().collect();
+    let mut lines = Vec::new();
+    // offsets stay in usize, the end of a full 64KiB program doesn't fit in a u16
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = origin.wrapping_add(offset as u16);
+        match code.get(&address) {
+            Some(instruction) => {
+                let size = instruction.size() as usize;
+                lines.push(Line {
+                    address,
+                    bytes: rom[offset..offset + size].to_vec(),
+                    kind: LineKind::Code(*instruction),
+                });
+                offset += size;
+            }
+            None => {
+                // group data bytes until the next code or label
+                let mut length = 1;
+                while length < DATA_PER_LINE && offset + length < rom.len() {
+                    let next = origin.wrapping_add((offset + length) as u16);
+                    if starts.contains(&next) || labels.contains_key(&next) {
+                        break;
+                    }
+                    length += 1;
+                }
+                lines.push(Line {
+                    address,
+                    bytes: rom[offset..offset + length].to_vec(),
+                    kind: LineKind::Data,
+                });
+                offset += length;
+            }
+        }
+    }
+
+    // drop labels pointing outside the program or into the middle of a line
+    let line_starts: BTreeSet<u16> = lines.iter().map(|line| line.address).collect();
+    labels.retain(|address, _| line_starts.contains(address));
+
+    Disassembly { lines, labels }
+}
+
+fn label(prefix: &str, address: u16) -> String {
+    format!("{}_{:03X}", prefix, address)
+}
+
+impl Disassembly {
+    pub fn render(&self, syntax: Syntax) -> String {
+        let mut output = String::new();
+
+        for line in &self.lines {
+            if let Some(name) = self.labels.get(&line.address) {
+                match syntax {
+                    Syntax::Cowgod => output.push_str(&format!("{}:\n", name)),
+                    Syntax::Octo => output.push_str(&format!(": {}\n", name)),
+                }
+            }
+
+            let raw: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text = match &line.kind {
+                LineKind::Code(instruction) => mnemonic(instruction, syntax, &self.labels),
+                LineKind::Data => {
+                    let bytes: Vec<String> =
+                        line.bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+                    match syntax {
+                        Syntax::Cowgod => format!("DB {}", bytes.join(", ")),
+                        Syntax::Octo => bytes.join(" "),
+                    }
+                }
+            };
+            let comment = match syntax {
+                Syntax::Cowgod => ';',
+                Syntax::Octo => '#',
+            };
+            output.push_str(&format!(
+                "    {:<24} {} {:04X}  {}\n",
+                text, comment, line.address, raw
+            ));
+        }
+        output
+    }
+}
+
+// textual form of an instruction, addresses with a label are shown by name
+pub fn mnemonic(
+    instruction: &Instruction,
+    syntax: Syntax,
+    labels: &BTreeMap<u16, String>,
+) -> String {
+    match syntax {
+        Syntax::Cowgod => cowgod(instruction, labels),
+        Syntax::Octo => octo(instruction, labels),
+    }
+}
+
+fn target(labels: &BTreeMap<u16, String>, address: u16) -> String {
+    match labels.get(&address) {
+        Some(name) => name.clone(),
+        None => format!("0x{:03X}", address),
+    }
+}
+
+fn cowgod(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    let address = |address: u16| target(labels, address);
+    match *instruction {
+        Instruction::ScrollDown(n) => format!("SCD {}", n),
+        Instruction::ScrollUp(n) => format!("SCU {}", n),
+        Instruction::ClearDisplay => "CLS".to_string(),
+        Instruction::Return => "RET".to_string(),
+        Instruction::ScrollRight => "SCR".to_string(),
+        Instruction::ScrollLeft => "SCL".to_string(),
+        Instruction::Exit => "EXIT".to_string(),
+        Instruction::LowRes => "LOW".to_string(),
+        Instruction::HighRes => "HIGH".to_string(),
+        Instruction::Jump(nnn) => format!("JP {}", address(nnn)),
+        Instruction::Call(nnn) => format!("CALL {}", address(nnn)),
+        Instruction::SkipIfEqualsByte(x, kk) => format!("SE V{:X}, 0x{:02X}", x, kk),
+        Instruction::SkipIfNotEqualsByte(x, kk) => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        Instruction::SkipIfEqual(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        Instruction::SaveRange(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+        Instruction::LoadRange(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+        Instruction::LoadByte(x, kk) => format!("LD V{:X}, 0x{:02X}", x, kk),
+        Instruction::AddByte(x, kk) => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        Instruction::Move(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Instruction::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        Instruction::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Instruction::Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Instruction::Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Instruction::ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Instruction::ReverseSub(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Instruction::ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        Instruction::SkipIfNotEqual(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Instruction::LoadI(nnn) => format!("LD I, {}", address(nnn)),
+        Instruction::JumpPlusZero(nnn) => format!("JP V0, {}", address(nnn)),
+        Instruction::Random(x, kk) => format!("RND V{:X}, 0x{:02X}", x, kk),
+        Instruction::Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Instruction::DrawLarge(x, y) => format!("DRW V{:X}, V{:X}, 0", x, y),
+        Instruction::SkipIfPressed(x) => format!("SKP V{:X}", x),
+        Instruction::SkipIfNotPressed(x) => format!("SKNP V{:X}", x),
+        Instruction::LoadILong(nnnn) => format!("LD I, LONG {}", address(nnnn)),
+        Instruction::SelectPlanes(n) => format!("PLANE {}", n),
+        Instruction::LoadAudio => "AUDIO".to_string(),
+        Instruction::LoadDelayTimer(x) => format!("LD V{:X}, DT", x),
+        Instruction::WaitForKeyPress(x) => format!("LD V{:X}, K", x),
+        Instruction::SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+        Instruction::SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+        Instruction::AddToI(x) => format!("ADD I, V{:X}", x),
+        Instruction::LoadSprite(x) => format!("LD F, V{:X}", x),
+        Instruction::LoadLargeSprite(x) => format!("LD HF, V{:X}", x),
+        Instruction::BCDRepresentation(x) => format!("LD B, V{:X}", x),
+        Instruction::SetPitch(x) => format!("PITCH V{:X}", x),
+        Instruction::StoreRegisters(x) => format!("LD [I], V{:X}", x),
+        Instruction::LoadRegisters(x) => format!("LD V{:X}, [I]", x),
+        Instruction::StoreFlags(x) => format!("LD R, V{:X}", x),
+        Instruction::LoadFlags(x) => format!("LD V{:X}, R", x),
+    }
+}
+
+fn octo(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    let address = |address: u16| target(labels, address);
+    match *instruction {
+        Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+        Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+        Instruction::ClearDisplay => "clear".to_string(),
+        Instruction::Return => "return".to_string(),
+        Instruction::ScrollRight => "scroll-right".to_string(),
+        Instruction::ScrollLeft => "scroll-left".to_string(),
+        Instruction::Exit => "exit".to_string(),
+        Instruction::LowRes => "lores".to_string(),
+        Instruction::HighRes => "hires".to_string(),
+        Instruction::Jump(nnn) => format!("jump {}", address(nnn)),
+        // a bare label name is a call in Octo, raw addresses need `:call`
+        Instruction::Call(nnn) => match labels.get(&nnn) {
+            Some(name) => name.clone(),
+            None => format!(":call {}", address(nnn)),
+        },
+        // Octo conditionals read as "run the next line if", the opposite of a skip
+        Instruction::SkipIfEqualsByte(x, kk) => format!("if v{:x} != 0x{:02X} then", x, kk),
+        Instruction::SkipIfNotEqualsByte(x, kk) => format!("if v{:x} == 0x{:02X} then", x, kk),
+        Instruction::SkipIfEqual(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        Instruction::SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        Instruction::LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        Instruction::LoadByte(x, kk) => format!("v{:x} := 0x{:02X}", x, kk),
+        Instruction::AddByte(x, kk) => format!("v{:x} += 0x{:02X}", x, kk),
+        Instruction::Move(x, y) => format!("v{:x} := v{:x}", x, y),
+        Instruction::Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        Instruction::And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Instruction::Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Instruction::Add(x, y) => format!("v{:x} += v{:x}", x, y),
+        Instruction::Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+        Instruction::ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        Instruction::ReverseSub(x, y) => format!("v{:x} =- v{:x}", x, y),
+        Instruction::ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        Instruction::SkipIfNotEqual(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        Instruction::LoadI(nnn) => format!("i := {}", address(nnn)),
+        Instruction::JumpPlusZero(nnn) => format!("jump0 {}", address(nnn)),
+        Instruction::Random(x, kk) => format!("v{:x} := random 0x{:02X}", x, kk),
+        Instruction::Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::DrawLarge(x, y) => format!("sprite v{:x} v{:x} 0", x, y),
+        Instruction::SkipIfPressed(x) => format!("if v{:x} -key then", x),
+        Instruction::SkipIfNotPressed(x) => format!("if v{:x} key then", x),
+        Instruction::LoadILong(nnnn) => format!("i := long {}", address(nnnn)),
+        Instruction::SelectPlanes(n) => format!("plane {}", n),
+        Instruction::LoadAudio => "audio".to_string(),
+        Instruction::LoadDelayTimer(x) => format!("v{:x} := delay", x),
+        Instruction::WaitForKeyPress(x) => format!("v{:x} := key", x),
+        Instruction::SetDelayTimer(x) => format!("delay := v{:x}", x),
+        Instruction::SetSoundTimer(x) => format!("buzzer := v{:x}", x),
+        Instruction::AddToI(x) => format!("i += v{:x}", x),
+        Instruction::LoadSprite(x) => format!("i := hex v{:x}", x),
+        Instruction::LoadLargeSprite(x) => format!("i := bighex v{:x}", x),
+        Instruction::BCDRepresentation(x) => format!("bcd v{:x}", x),
+        Instruction::SetPitch(x) => format!("pitch := v{:x}", x),
+        Instruction::StoreRegisters(x) => format!("save v{:x}", x),
+        Instruction::LoadRegisters(x) => format!("load v{:x}", x),
+        Instruction::StoreFlags(x) => format!("saveflags v{:x}", x),
+        Instruction::LoadFlags(x) => format!("loadflags v{:x}", x),
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", cowgod(self, &BTreeMap::new()))
+    }
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Syntax, String> {
+        match value.to_lowercase().as_str() {
+            "cowgod" => Ok(Syntax::Cowgod),
+            "octo" => Ok(Syntax::Octo),
+            _ => Err(format!(
+                "unknown syntax `{}` (expected cowgod or octo)",
+                value
+            )),
+        }
+    }
+}
+
+// disassemble a ROM loaded at the usual program address
+pub fn disassemble_rom(rom: &[u8]) -> Disassembly {
+    disassemble(rom, PROGRAM_MEMORY_OFFSET as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::EXTENDED_MEMORY_SIZE;
+
+    const MAX_ROM_SIZE: usize = EXTENDED_MEMORY_SIZE - PROGRAM_MEMORY_OFFSET;
+
+    #[test]
+    fn disassembles_maximum_size_code() {
+        // LD V0, 00 all the way to the end of memory
+        let rom = [0x60, 0x00].repeat(MAX_ROM_SIZE / 2);
+        let disassembly = disassemble_rom(&rom);
+        assert_eq!(disassembly.lines.len(), MAX_ROM_SIZE / 2);
+        assert_eq!(disassembly.lines.last().unwrap().address, 0xFFFE);
+    }
+
+    #[test]
+    fn disassembles_maximum_size_data() {
+        // exit, then nothing but data
+        let mut rom = vec![0xFF; MAX_ROM_SIZE];
+        rom[..2].copy_from_slice(&[0x00, 0xFD]);
+        let disassembly = disassemble_rom(&rom);
+        let last = disassembly.lines.last().unwrap();
+        assert_eq!(
+            last.address as usize + last.bytes.len(),
+            EXTENDED_MEMORY_SIZE
+        );
+        assert!(disassembly
+            .render(Syntax::Octo)
+            .ends_with("# FFFA  FFFFFFFFFFFF\n"));
+    }
+}
@@ -1,43 +1,159 @@
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 
+// SUPER-CHIP high resolution mode
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+
+pub const BUFFER_SIZE: usize = HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT;
+
+// XO-CHIP bitplanes, every buffer pixel holds one bit per plane
+pub const PLANE_COUNT: usize = 2;
+pub const ALL_PLANES: u8 = 0b11;
+
 pub struct Display {
-    pub buffer: [u8; DISPLAY_WIDTH * DISPLAY_HEIGHT],
-    pub buffer_update: bool,
+    pub buffer: [u8; BUFFER_SIZE],
+    dirty_rows: [bool; HIRES_DISPLAY_HEIGHT], // rows changed since the screen was last presented
+    hires: bool,
+    planes: u8, // bitmask of the planes drawing operations apply to
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
 }
 
 impl Display {
     pub fn new() -> Display {
         Display {
-            buffer: [0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
-            buffer_update: false,
+            buffer: [0; BUFFER_SIZE],
+            dirty_rows: [true; HIRES_DISPLAY_HEIGHT],
+            hires: false,
+            planes: 0b01,
         }
     }
 
     pub fn clear(&mut self) {
-        self.buffer = [0; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        // only the selected planes get cleared
+        for pixel in self.buffer.iter_mut() {
+            *pixel &= !self.planes;
+        }
+        self.mark_dirty();
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & ALL_PLANES;
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn plane_count(&self) -> usize {
+        self.planes.count_ones() as usize
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_WIDTH
+        } else {
+            DISPLAY_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_DISPLAY_HEIGHT
+        } else {
+            DISPLAY_HEIGHT
+        }
+    }
+
+    pub fn set_hires(&mut self, hires: bool) {
+        // the buffer layout depends on the resolution so start over
+        self.hires = hires;
+        self.buffer = [0; BUFFER_SIZE];
+        self.mark_dirty();
+    }
+
+    // sprite data holds one sprite per selected plane, one after the other
+    pub fn draw(&mut self, x: u8, y: u8, memory: &[u8], clip: bool) -> bool {
+        self.draw_planes(x, y, memory, clip, |sprite| {
+            sprite.iter().map(|block| (*block as u16) << 8).collect()
+        })
+    }
+
+    pub fn draw_large(&mut self, x: u8, y: u8, memory: &[u8], clip: bool) -> bool {
+        // 16x16 sprites are stored as two bytes per row
+        self.draw_planes(x, y, memory, clip, |sprite| {
+            sprite
+                .chunks(2)
+                .map(|pair| (pair[0] as u16) << 8 | *pair.get(1).unwrap_or(&0) as u16)
+                .collect()
+        })
     }
 
-    pub fn draw(&mut self, x: u8, y: u8, memory: &[u8]) -> bool {
+    fn draw_planes<F>(&mut self, x: u8, y: u8, memory: &[u8], clip: bool, to_rows: F) -> bool
+    where
+        F: Fn(&[u8]) -> Vec<u16>,
+    {
+        let count = self.plane_count();
+        if count == 0 || memory.is_empty() {
+            return false;
+        }
+
         let mut collision = false;
-        self.buffer_update = true;
+        let mut sprites = memory.chunks(memory.len() / count);
 
-        for (block_offset, block) in memory.iter().enumerate() {
-            let pos_y = (y as usize + block_offset) % DISPLAY_HEIGHT;
-            for pixel_offset in 0..8 {
+        for plane in 0..PLANE_COUNT {
+            let mask = 1 << plane;
+            if self.planes & mask == 0 {
+                continue;
+            }
+            if let Some(sprite) = sprites.next() {
+                collision |= self.draw_rows(x, y, &to_rows(sprite), clip, mask);
+            }
+        }
+        collision
+    }
+
+    fn draw_rows(&mut self, x: u8, y: u8, rows: &[u16], clip: bool, mask: u8) -> bool {
+        let mut collision = false;
+
+        let width = self.width();
+        let height = self.height();
+
+        // the starting position always wraps around the screen
+        let x = x as usize % width;
+        let y = y as usize % height;
+
+        for (block_offset, block) in rows.iter().enumerate() {
+            if clip && y + block_offset >= height {
+                break;
+            }
+            let pos_y = (y + block_offset) % height;
+            self.dirty_rows[pos_y] = true;
+            for pixel_offset in 0..16 {
+                if clip && x + pixel_offset >= width {
+                    break;
+                }
                 // get buffer index from x and y coordinates
-                let pos_x = (x as usize + pixel_offset) % DISPLAY_WIDTH;
-                let idx = self.from_coordinates(pos_x, pos_y);
+                let pos_x = (x + pixel_offset) % width;
+                let idx = self.index_of(pos_x, pos_y);
 
-                let buffer_pixel = self.buffer[idx];
-                let sprite_pixel = (block >> (7 - pixel_offset)) & 1;
+                let buffer_pixel = self.buffer[idx] & mask;
+                let sprite_pixel = if (block >> (15 - pixel_offset)) & 1 == 1 {
+                    mask
+                } else {
+                    0
+                };
 
                 // xor the buffer pixel with the sprite pixel
-                let pixel = buffer_pixel ^ sprite_pixel;
-                self.buffer[idx] = pixel;
+                self.buffer[idx] ^= sprite_pixel;
 
                 // check if collision detected
-                if sprite_pixel == 1 && pixel == 0 {
+                if sprite_pixel != 0 && buffer_pixel != 0 {
                     collision = true
                 }
             }
@@ -45,11 +161,226 @@ impl Display {
         collision
     }
 
-    pub fn from_coordinates(&self, x: usize, y: usize) -> usize {
-        (x + DISPLAY_WIDTH * y) as usize
+    pub fn scroll_down(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
+
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let source = if y >= n {
+                    Some(self.index_of(x, y - n))
+                } else {
+                    None
+                };
+                self.move_pixel(source, self.index_of(x, y));
+            }
+        }
+        self.mark_dirty();
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
+
+        for y in 0..height {
+            for x in 0..width {
+                let source = if y + n < height {
+                    Some(self.index_of(x, y + n))
+                } else {
+                    None
+                };
+                self.move_pixel(source, self.index_of(x, y));
+            }
+        }
+        self.mark_dirty();
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
+
+        for y in 0..height {
+            for x in (0..width).rev() {
+                let source = if x >= n {
+                    Some(self.index_of(x - n, y))
+                } else {
+                    None
+                };
+                self.move_pixel(source, self.index_of(x, y));
+            }
+        }
+        self.mark_dirty();
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
+
+        for y in 0..height {
+            for x in 0..width {
+                let source = if x + n < width {
+                    Some(self.index_of(x + n, y))
+                } else {
+                    None
+                };
+                self.move_pixel(source, self.index_of(x, y));
+            }
+        }
+        self.mark_dirty();
+    }
+
+    // scrolling only moves the pixels of the selected planes
+    fn move_pixel(&mut self, source: Option<usize>, target: usize) {
+        let value = source.map(|idx| self.buffer[idx]).unwrap_or(0) & self.planes;
+        self.buffer[target] = (self.buffer[target] & !self.planes) | value;
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.buffer[self.index_of(x, y)]
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    pub fn index_of(&self, x: usize, y: usize) -> usize {
+        x + self.width() * y
     }
 
     pub fn should_update(&self) -> bool {
-        self.buffer_update
+        self.dirty_rows.contains(&true)
+    }
+
+    // the rows that changed since the last `mark_clean`, in the current resolution
+    pub fn dirty_rows(&self) -> impl Iterator<Item = usize> + '_ {
+        self.dirty_rows[..self.height()]
+            .iter()
+            .enumerate()
+            .filter(|(_, dirty)| **dirty)
+            .map(|(row, _)| row)
+    }
+
+    // redraw everything, e.g. after writing to `buffer` directly
+    pub fn mark_dirty(&mut self) {
+        self.dirty_rows = [true; HIRES_DISPLAY_HEIGHT];
+    }
+
+    // call after the screen has been presented
+    pub fn mark_clean(&mut self) {
+        self.dirty_rows = [false; HIRES_DISPLAY_HEIGHT];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every pixel that's on, as (x, y, planes)
+    fn lit(display: &Display) -> Vec<(usize, usize, u8)> {
+        let mut pixels = Vec::new();
+        for y in 0..display.height() {
+            for x in 0..display.width() {
+                if display.pixel(x, y) != 0 {
+                    pixels.push((x, y, display.pixel(x, y)));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn scrolls_in_every_direction() {
+        for hires in [false, true].iter().copied() {
+            let mut display = Display::new();
+            display.set_hires(hires);
+            display.draw(10, 10, &[0x80], false);
+
+            display.scroll_down(4);
+            assert_eq!(lit(&display), [(10, 14, 1)]);
+            display.scroll_right(4);
+            assert_eq!(lit(&display), [(14, 14, 1)]);
+            display.scroll_up(4);
+            assert_eq!(lit(&display), [(14, 10, 1)]);
+            display.scroll_left(4);
+            assert_eq!(lit(&display), [(10, 10, 1)]);
+
+            // pixels scrolled off the screen are gone, nothing wraps around
+            display.scroll_left(11);
+            assert_eq!(lit(&display), []);
+        }
+    }
+
+    #[test]
+    fn scrolls_only_the_selected_planes() {
+        let mut display = Display::new();
+        display.select_planes(ALL_PLANES);
+        display.draw(0, 0, &[0x80, 0x80], false);
+        assert_eq!(lit(&display), [(0, 0, 0b11)]);
+
+        display.select_planes(0b10);
+        display.scroll_down(1);
+        assert_eq!(lit(&display), [(0, 0, 0b01), (0, 1, 0b10)]);
+    }
+
+    #[test]
+    fn switching_resolution_starts_over() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xFF], false);
+        display.mark_clean();
+        assert!(!display.should_update());
+
+        display.set_hires(true);
+        assert_eq!((display.width(), display.height()), (128, 64));
+        assert_eq!(lit(&display), []);
+        assert_eq!(display.dirty_rows().count(), HIRES_DISPLAY_HEIGHT);
+
+        display.mark_clean();
+        display.set_hires(false);
+        assert_eq!((display.width(), display.height()), (64, 32));
+        assert_eq!(display.dirty_rows().count(), DISPLAY_HEIGHT);
+    }
+
+    #[test]
+    fn drawing_marks_its_rows_dirty() {
+        let mut display = Display::new();
+        display.mark_clean();
+        display.draw(0, 5, &[0x80, 0x80], false);
+        assert_eq!(display.dirty_rows().collect::<Vec<_>>(), [5, 6]);
+    }
+
+    #[test]
+    fn clears_only_the_selected_planes() {
+        let mut display = Display::new();
+        display.select_planes(ALL_PLANES);
+        display.draw(0, 0, &[0x80, 0x80], false);
+        display.mark_clean();
+
+        display.select_planes(0b01);
+        display.clear();
+        assert_eq!(lit(&display), [(0, 0, 0b10)]);
+        assert!(display.should_update());
+
+        display.select_planes(ALL_PLANES);
+        display.clear();
+        assert_eq!(lit(&display), []);
+    }
+
+    #[test]
+    fn draws_one_sprite_per_plane() {
+        let mut display = Display::new();
+        display.select_planes(ALL_PLANES);
+        // a row for the first plane, then one for the second
+        assert!(!display.draw(0, 0, &[0x80, 0xC0], false));
+        assert_eq!(lit(&display), [(0, 0, 0b11), (1, 0, 0b10)]);
+
+        // a collision on either plane counts
+        display.select_planes(0b10);
+        assert!(display.draw(1, 0, &[0x80], false));
+        assert_eq!(lit(&display), [(0, 0, 0b11)]);
+
+        // with no planes selected nothing is drawn
+        display.select_planes(0);
+        assert!(!display.draw(4, 4, &[0xFF], false));
+        assert_eq!(lit(&display), [(0, 0, 0b11)]);
     }
 }
@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chip8Error {
+    // the ROM doesn't fit in the program memory
+    RomTooLarge {
+        size: usize,
+        max: usize,
+    },
+    // the opcode doesn't decode to a known instruction
+    UnknownOpcode {
+        pc: u16,
+        opcode: u16,
+    },
+    // returning from a subroutine with an empty stack
+    StackUnderflow {
+        pc: u16,
+        opcode: u16,
+    },
+    // calling a subroutine with a full stack
+    StackOverflow {
+        pc: u16,
+        opcode: u16,
+    },
+    // the instruction accessed memory past the end of the address space
+    MemoryOutOfBounds {
+        pc: u16,
+        opcode: u16,
+        address: usize,
+    },
+    // a speed no frame would ever run at, see `is_valid_speed`
+    InvalidSpeed {
+        instructions: f64,
+    },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes but only {} fit in memory", size, max)
+            }
+            Chip8Error::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:04X} at {:04X}", opcode, pc)
+            }
+            Chip8Error::StackUnderflow { pc, opcode } => {
+                write!(f, "stack underflow ({:04X} at {:04X})", opcode, pc)
+            }
+            Chip8Error::StackOverflow { pc, opcode } => {
+                write!(f, "stack overflow ({:04X} at {:04X})", opcode, pc)
+            }
+            Chip8Error::MemoryOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "memory access out of bounds at {:04X} ({:04X} at {:04X})",
+                address, opcode, pc
+            ),
+            Chip8Error::InvalidSpeed { instructions } => write!(
+                f,
+                "invalid speed of {} instructions per frame (it has to be more than 0)",
+                instructions
+            ),
+        }
+    }
+}
+
+impl Error for Chip8Error {}
@@ -0,0 +1,132 @@
+use crate::display::Display;
+use crate::palette::{Color, Palette, PALETTE_SIZE};
+use std::str::FromStr;
+
+// ways to hide the flicker of sprites that are erased and redrawn every frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Off,
+    Decay, // lit pixels fade out over a few frames like the phosphor of a CRT
+    Blend, // pixels lit in the previous frame stay lit (OR of the last two frames)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterSettings {
+    pub mode: FilterMode,
+    // how much of the previous frames shows through, from 0.0 to 1.0: the
+    // brightness kept every frame for `Decay`, of the previous frame for `Blend`
+    pub strength: f32,
+}
+
+impl Default for FilterSettings {
+    fn default() -> FilterSettings {
+        FilterSettings {
+            mode: FilterMode::Off,
+            strength: 0.6,
+        }
+    }
+}
+
+// how much every pixel shows of each palette color, the background fills in the rest
+pub struct Filter {
+    settings: FilterSettings,
+    previous: Vec<u8>,
+    weights: Vec<[f32; PALETTE_SIZE]>,
+}
+
+impl Filter {
+    pub fn new(settings: FilterSettings) -> Filter {
+        Filter {
+            settings: FilterSettings {
+                strength: settings.strength.clamp(0.0, 1.0),
+                ..settings
+            },
+            previous: Vec::new(),
+            weights: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.mode != FilterMode::Off
+    }
+
+    // call once per frame with the finished screen
+    pub fn record(&mut self, display: &Display) {
+        if self.settings.mode == FilterMode::Off {
+            return;
+        }
+        let pixels = &display.buffer[..display.width() * display.height()];
+        // the resolution changed, start over
+        if self.weights.len() != pixels.len() {
+            self.weights = vec![[0.0; PALETTE_SIZE]; pixels.len()];
+            self.previous = pixels.to_vec();
+        }
+
+        let strength = self.settings.strength;
+        for (index, &pixel) in pixels.iter().enumerate() {
+            let weights = &mut self.weights[index];
+            match self.settings.mode {
+                FilterMode::Decay => {
+                    for weight in weights.iter_mut() {
+                        *weight *= strength;
+                    }
+                }
+                _ => *weights = [0.0; PALETTE_SIZE],
+            }
+            let previous = self.previous[index] as usize;
+            if self.settings.mode == FilterMode::Blend && previous != 0 {
+                weights[previous] = strength;
+            }
+            // lit pixels light up at once and cover whatever was fading there
+            if pixel != 0 {
+                *weights = [0.0; PALETTE_SIZE];
+                weights[pixel as usize] = 1.0;
+            }
+        }
+        self.previous.copy_from_slice(pixels);
+    }
+
+    // the color to draw a pixel in, `None` for the background
+    pub fn color(&self, display: &Display, index: usize, palette: &Palette) -> Option<Color> {
+        // nothing recorded for this screen yet, draw it as it is
+        if self.weights.len() != display.width() * display.height() {
+            let pixel = display.buffer[index];
+            return (pixel != 0).then(|| palette.color(pixel));
+        }
+
+        let weights = &self.weights[index];
+        let total: f32 = weights[1..].iter().sum();
+        if total < 1.0 / 255.0 {
+            return None;
+        }
+        let mut color = palette.color(0).map(|component| component * (1.0 - total));
+        for (value, weight) in weights.iter().enumerate().skip(1) {
+            for (component, other) in color.iter_mut().zip(palette.color(value as u8)) {
+                *component += other * weight;
+            }
+        }
+        Some(color)
+    }
+
+    // forget the history, e.g. after loading a save state
+    pub fn clear(&mut self) {
+        self.previous.clear();
+        self.weights.clear();
+    }
+}
+
+impl FromStr for FilterMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<FilterMode, String> {
+        match value.to_lowercase().as_str() {
+            "off" | "none" => Ok(FilterMode::Off),
+            "decay" => Ok(FilterMode::Decay),
+            "blend" => Ok(FilterMode::Blend),
+            _ => Err(format!(
+                "unknown filter `{}` (expected off, decay or blend)",
+                value
+            )),
+        }
+    }
+}
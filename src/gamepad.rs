@@ -0,0 +1,181 @@
+use piston_window::HatState;
+use std::sync::mpsc::{self, Receiver};
+
+// what a gamepad did, in the terms piston uses for its controller events
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GamepadEvent {
+    Button(u8, bool), // pressed or released
+    Axis(u8, f64),    // position from -1.0 to 1.0
+    Hat(HatState),
+}
+
+// USB gamepads read straight from the operating system, because piston's
+// default glutin backend never reports controller events
+//
+// only Linux is supported so far, through the joystick devices in /dev/input
+pub struct Gamepads {
+    receiver: Receiver<GamepadEvent>,
+}
+
+impl Gamepads {
+    // watch for gamepads in the background, they can be plugged in at any time
+    pub fn open() -> Gamepads {
+        let (sender, receiver) = mpsc::channel();
+        #[cfg(target_os = "linux")]
+        linux::watch(sender);
+        #[cfg(not(target_os = "linux"))]
+        drop(sender);
+        Gamepads { receiver }
+    }
+
+    // everything that happened since the last call
+    pub fn events(&self) -> impl Iterator<Item = GamepadEvent> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::GamepadEvent;
+    use piston_window::HatState;
+    use std::collections::BTreeSet;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::sync::mpsc::Sender;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // struct js_event from linux/joystick.h
+    const EVENT_SIZE: usize = 8;
+    const JS_EVENT_BUTTON: u8 = 0x01;
+    const JS_EVENT_AXIS: u8 = 0x02;
+    const JS_EVENT_INIT: u8 = 0x80;
+
+    // JSIOCGAXMAP, the input event code behind every axis number
+    const JSIOCGAXMAP: libc::c_ulong = 0x8040_6a32;
+    const ABS_CNT: usize = 0x40;
+    const ABS_HAT0X: u8 = 0x10;
+    const ABS_HAT0Y: u8 = 0x11;
+
+    const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn watch(sender: Sender<GamepadEvent>) {
+        let open: Arc<Mutex<BTreeSet<PathBuf>>> = Arc::default();
+        thread::spawn(move || loop {
+            let devices = fs::read_dir("/dev/input").into_iter().flatten().flatten();
+            for device in devices {
+                let path = device.path();
+                let is_joystick = device.file_name().to_string_lossy().starts_with("js");
+                if !is_joystick || !open.lock().unwrap().insert(path.clone()) {
+                    continue;
+                }
+                let (sender, open) = (sender.clone(), open.clone());
+                thread::spawn(move || {
+                    // gamepads that can't be opened are tried again on the next scan
+                    if let Ok(file) = File::open(&path) {
+                        read_events(file, &sender);
+                    }
+                    open.lock().unwrap().remove(&path);
+                });
+            }
+            thread::sleep(SCAN_INTERVAL);
+        });
+    }
+
+    // until the gamepad is unplugged or the window is gone
+    pub(super) fn read_events(mut file: File, sender: &Sender<GamepadEvent>) {
+        let mut axis_codes = [0u8; ABS_CNT];
+        // SAFETY: the kernel writes at most ABS_CNT bytes into the buffer, and
+        // leaves it alone for anything that isn't a joystick
+        unsafe { libc::ioctl(file.as_raw_fd(), JSIOCGAXMAP, axis_codes.as_mut_ptr()) };
+
+        let mut hat = (0, 0);
+        let mut event = [0u8; EVENT_SIZE];
+        while file.read_exact(&mut event).is_ok() {
+            let value = i16::from_ne_bytes([event[4], event[5]]);
+            let (kind, number) = (event[6], event[7]);
+            // the current state sent on opening isn't something the player did
+            if kind & JS_EVENT_INIT != 0 {
+                continue;
+            }
+
+            let event = match kind {
+                JS_EVENT_BUTTON => GamepadEvent::Button(number, value != 0),
+                JS_EVENT_AXIS => match axis_codes.get(number as usize) {
+                    // the D-pad of most gamepads is a hat reported as two axes
+                    Some(&ABS_HAT0X) => {
+                        hat.0 = value.signum();
+                        GamepadEvent::Hat(hat_state(hat))
+                    }
+                    Some(&ABS_HAT0Y) => {
+                        hat.1 = value.signum();
+                        GamepadEvent::Hat(hat_state(hat))
+                    }
+                    _ => GamepadEvent::Axis(number, value as f64 / i16::MAX as f64),
+                },
+                _ => continue,
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+    }
+
+    // down is positive on the Y axis
+    fn hat_state((x, y): (i16, i16)) -> HatState {
+        match (x, y) {
+            (0, -1) => HatState::Up,
+            (0, 1) => HatState::Down,
+            (-1, 0) => HatState::Left,
+            (1, 0) => HatState::Right,
+            (1, -1) => HatState::RightUp,
+            (1, 1) => HatState::RightDown,
+            (-1, -1) => HatState::LeftUp,
+            (-1, 1) => HatState::LeftDown,
+            _ => HatState::Centered,
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::linux::read_events;
+    use super::GamepadEvent;
+    use std::fs::{self, File};
+    use std::sync::mpsc;
+
+    fn event(value: i16, kind: u8, number: u8) -> Vec<u8> {
+        let mut bytes = 0u32.to_ne_bytes().to_vec();
+        bytes.extend(value.to_ne_bytes());
+        bytes.extend([kind, number]);
+        bytes
+    }
+
+    #[test]
+    fn reads_joystick_events() {
+        // a regular file has no axis map, so every axis is a plain one
+        let path = std::env::temp_dir().join(format!("rusty-chip8-js-{}", std::process::id()));
+        let events = [
+            event(1, 0x81, 0), // initial state, skipped
+            event(1, 0x01, 2),
+            event(0, 0x01, 2),
+            event(-32767, 0x02, 1),
+        ];
+        fs::write(&path, events.concat()).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        read_events(File::open(&path).unwrap(), &sender);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [
+                GamepadEvent::Button(2, true),
+                GamepadEvent::Button(2, false),
+                GamepadEvent::Axis(1, -1.0),
+            ]
+        );
+    }
+}
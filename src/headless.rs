@@ -0,0 +1,264 @@
+use crate::audio::AudioSink;
+use crate::chip8::Chip8;
+use crate::display::Display;
+use crate::error::Chip8Error;
+use crate::palette::Palette;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::{self, Write};
+
+// how long a headless run lasts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunLength {
+    Cycles(usize),
+    Frames(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub frame: usize,
+    pub key: usize,
+    pub pressed: bool,
+}
+
+// scripted key presses, one `<frame> <down|up> <key>` event per line
+#[derive(Debug, Clone, Default)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn parse(source: &str) -> Result<KeyScript, String> {
+        let mut events = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            // everything after a `#` is a comment
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: &str| format!("line {}: {}", number + 1, message);
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Err(error("expected `<frame> <down|up> <key>`"));
+            }
+
+            let frame = parts[0]
+                .parse()
+                .map_err(|_| error("invalid frame number"))?;
+            let pressed = match parts[1] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(error("expected `down` or `up`")),
+            };
+            let key = usize::from_str_radix(parts[2], 16)
+                .ok()
+                .filter(|key| *key < 16)
+                .ok_or_else(|| error("keys go from 0 to F"))?;
+
+            events.push(KeyEvent {
+                frame,
+                key,
+                pressed,
+            });
+        }
+
+        // events of the same frame keep their order
+        events.sort_by_key(|event| event.frame);
+        Ok(KeyScript { events })
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+}
+
+// run a program without a window, returns the number of frames emulated
+pub fn run_headless(
+    chip8: &mut Chip8,
+    length: RunLength,
+    script: &KeyScript,
+    sink: &mut dyn AudioSink,
+) -> Result<usize, Chip8Error> {
+    let mut events = script.events().iter().peekable();
+    let mut cycles = 0;
+    let mut frame = 0;
+
+    loop {
+        match length {
+            RunLength::Cycles(limit) if cycles >= limit => break,
+            RunLength::Frames(limit) if frame >= limit => break,
+            _ => {}
+        }
+
+        while let Some(event) = events.next_if(|event| event.frame <= frame) {
+            if event.pressed {
+                chip8.handle_key_down(event.key);
+            } else {
+                chip8.handle_key_up(event.key);
+            }
+        }
+
+        cycles += match length {
+            RunLength::Cycles(limit) => run_frame_until(chip8, limit - cycles)?,
+            RunLength::Frames(_) => chip8.run_frame()?,
+        };
+        sink.frame(chip8.sound());
+        frame += 1;
+
+        if chip8.is_halted() {
+            break;
+        }
+        // no more instructions would ever run to reach a cycle limit
+        let stalled = chip8.is_waiting_for_key() && events.peek().is_none();
+        if stalled && matches!(length, RunLength::Cycles(_)) {
+            break;
+        }
+    }
+    Ok(frame)
+}
+
+// a frame that stops after `limit` instructions, the rest of it passes without
+// running any so the timers still tick, returns how many instructions ran
+fn run_frame_until(chip8: &mut Chip8, limit: usize) -> Result<usize, Chip8Error> {
+    chip8.begin_frame();
+    let mut count = 0;
+    while chip8.has_cycle_time() {
+        if count == limit || chip8.is_blocked() {
+            chip8.idle();
+        } else {
+            chip8.step()?;
+            count += 1;
+        }
+    }
+    chip8.end_frame();
+    Ok(count)
+}
+
+// one character per pixel, `#` for lit pixels and the plane number for XO-CHIP colors
+pub fn framebuffer_ascii(display: &Display) -> String {
+    let mut output = String::new();
+    for y in 0..display.height() {
+        for x in 0..display.width() {
+            output.push(match display.pixel(x, y) {
+                0 => '.',
+                1 => '#',
+                pixel => (b'0' + pixel) as char,
+            });
+        }
+        output.push('\n');
+    }
+    output
+}
+
+// the screen in the colors of `palette`, one PNG pixel per CHIP-8 pixel
+pub fn framebuffer_png<W: Write>(
+    display: &Display,
+    palette: &Palette,
+    writer: W,
+) -> io::Result<()> {
+    let width = display.width();
+    let height = display.height();
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let color = palette.color(display.pixel(x, y));
+            data.extend(color[..3].iter().map(|c| (c * 255.0).round() as u8));
+        }
+    }
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&data).map_err(png_error)
+}
+
+fn png_error(error: png::EncodingError) -> io::Error {
+    io::Error::other(error)
+}
+
+pub fn registers_json(chip8: &Chip8) -> String {
+    let state = json!({
+        "pc": chip8.pc(),
+        "i": chip8.i(),
+        "v": chip8.registers(),
+        "sp": chip8.sp(),
+        "stack": chip8.stack(),
+        "delay_timer": chip8.delay_timer(),
+        "sound_timer": chip8.sound_timer(),
+        "halted": chip8.is_halted(),
+        "waiting_for_key": chip8.is_waiting_for_key(),
+        "hires": chip8.display().is_hires(),
+        "seed": chip8.seed(),
+    });
+    serde_json::to_string_pretty(&state).expect("registers always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::quirks::{Quirks, Timing};
+
+    // ADD V0, 1 over and over, so V0 counts the instructions that ran
+    fn counter(timing: Timing) -> Chip8 {
+        let quirks = Quirks {
+            timing,
+            ..Quirks::default()
+        };
+        Chip8::new([0x70, 0x01].repeat(200), quirks).unwrap()
+    }
+
+    #[test]
+    fn cycle_limit_stops_mid_frame() {
+        for timing in [Timing::Fixed, Timing::Vip] {
+            let mut chip8 = counter(timing);
+            let length = RunLength::Cycles(25);
+            run_headless(&mut chip8, length, &KeyScript::default(), &mut NullSink).unwrap();
+            assert_eq!(chip8.registers()[0], 25);
+        }
+    }
+
+    #[test]
+    fn frame_limit_runs_whole_frames() {
+        let mut chip8 = counter(Timing::Fixed);
+        let length = RunLength::Frames(3);
+        let frames = run_headless(&mut chip8, length, &KeyScript::default(), &mut NullSink);
+        assert_eq!(frames.unwrap(), 3);
+        assert_eq!(chip8.registers()[0], 30);
+    }
+
+    #[test]
+    fn cycle_limit_gives_up_on_a_key_nobody_presses() {
+        // LD V0, K
+        let mut chip8 = Chip8::new(vec![0xF0, 0x0A], Quirks::default()).unwrap();
+        let length = RunLength::Cycles(100);
+        let frames = run_headless(&mut chip8, length, &KeyScript::default(), &mut NullSink);
+        assert_eq!(frames.unwrap(), 1);
+    }
+
+    #[test]
+    fn screenshot_uses_the_palette() {
+        let mut display = Display::new();
+        display.select_planes(0b11);
+        // plane 1, plane 2 and both
+        display.draw(0, 0, &[0xA0, 0x60], false);
+        let palette = Palette::named("hotdog").unwrap();
+
+        let mut png = Vec::new();
+        framebuffer_png(&display, &palette, &mut png).unwrap();
+        let (info, mut reader) = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        assert_eq!((info.width, info.height), (64, 32));
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+
+        let pixel = |x: usize| &data[x * 3..x * 3 + 3];
+        assert_eq!(pixel(0), [0xFF, 0x00, 0x00]);
+        assert_eq!(pixel(1), [0xFF, 0xFF, 0x00]);
+        assert_eq!(pixel(2), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(pixel(3), [0x00, 0x00, 0x00]);
+    }
+}
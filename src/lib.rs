@@ -0,0 +1,20 @@
+pub mod assembler;
+pub mod audio;
+pub mod chip8;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod error;
+pub mod filter;
+pub mod headless;
+pub mod movie;
+pub mod palette;
+pub mod quirks;
+pub mod rewind;
+pub mod scheduler;
+pub mod state;
+
+pub use chip8::Chip8;
+pub use display::Display;
+pub use error::Chip8Error;
+pub use quirks::{Preset, Quirks};
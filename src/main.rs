@@ -1,111 +1,1009 @@
-mod chip8;
-mod display;
+mod bindings;
+mod gamepad;
 
+use bindings::{Bindings, Hotkey, Input};
+use gamepad::Gamepads;
+use glutin::window::Fullscreen;
+use piston_window::texture::{CreateTexture, Format, UpdateTexture};
 use piston_window::*;
-use std::fs::File;
-use std::{env, io::Read};
-
-use chip8::Chip8;
-use display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
-
-fn from_key_code(key: Key) -> Option<usize> {
-    match key {
-        Key::D1 => Some(0x1),
-        Key::D2 => Some(0x2),
-        Key::D3 => Some(0x3),
-        Key::D4 => Some(0xC),
-        Key::Q => Some(0x4),
-        Key::W => Some(0x5),
-        Key::E => Some(0x6),
-        Key::R => Some(0xD),
-        Key::A => Some(0x7),
-        Key::S => Some(0x8),
-        Key::D => Some(0x9),
-        Key::F => Some(0xE),
-        Key::Z => Some(0xA),
-        Key::X => Some(0x0),
-        Key::C => Some(0xB),
-        Key::V => Some(0xF),
-        _ => None,
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+use rusty_chip8::assembler::assemble;
+use rusty_chip8::audio::{AudioSettings, AudioSink, NullSink, WavSink};
+use rusty_chip8::chip8::is_valid_speed;
+use rusty_chip8::debugger::{Debugger, StopReason};
+use rusty_chip8::disasm::{disassemble_rom, Syntax};
+use rusty_chip8::display::{
+    Display, BUFFER_SIZE, DISPLAY_HEIGHT, DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH,
+};
+use rusty_chip8::filter::{Filter, FilterSettings};
+use rusty_chip8::headless::{
+    framebuffer_ascii, framebuffer_png, registers_json, run_headless, KeyScript, RunLength,
+};
+use rusty_chip8::movie::{Movie, MovieSession};
+use rusty_chip8::palette::Palette;
+use rusty_chip8::quirks::Timing;
+use rusty_chip8::rewind::{Rewind, RewindSettings};
+use rusty_chip8::scheduler::Scheduler;
+use rusty_chip8::state::SaveState;
+use rusty_chip8::{Chip8, Chip8Error, Preset, Quirks};
+
+// the speeds the hotkeys step through, in instructions per frame
+const SPEEDS: [f64; 14] = [
+    1.0, 2.0, 3.0, 5.0, 7.0, 10.0, 15.0, 20.0, 30.0, 50.0, 100.0, 200.0, 500.0, 1000.0,
+];
+
+struct Options {
+    path: String,
+    preset: Preset,
+    audio: AudioSettings,
+    wav: Option<String>,
+    mute: bool,
+    headless: bool,
+    cycles: Option<usize>,
+    frames: Option<usize>,
+    keys: Option<String>,
+    screenshot: Option<String>,
+    registers: Option<String>,
+    debug: bool,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    rewind: RewindSettings,
+    seed: Option<u64>,
+    record: Option<String>,
+    play: Option<String>,
+    scale: Scale,
+    fullscreen: bool,
+    paused: bool,
+    filter: FilterSettings,
+    timing: Timing,
+    instructions_per_frame: Option<f64>,
+    display_wait: Option<bool>, // overrides the preset
+    key_release: Option<bool>,  // overrides the preset
+}
+
+// how the picture is scaled up to the window, it's centered either way
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scale {
+    Integer, // whole multiples only so every pixel has the same size
+    Fit,     // as large as the window allows
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Scale, String> {
+        match value.to_lowercase().as_str() {
+            "integer" => Ok(Scale::Integer),
+            "fit" => Ok(Scale::Fit),
+            _ => Err(format!(
+                "unknown scale mode `{}` (expected integer or fit)",
+                value
+            )),
+        }
     }
 }
 
-fn draw_screen(display: &mut Display, window: &mut PistonWindow, event: &Event) {
-    // skip if there are no display changes (performance check)
-    if !display.should_update() {
-        return;
+// parse the value that follows a command line flag
+fn flag_value<T, I>(args: &mut I, flag: &str) -> T
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+    I: Iterator<Item = String>,
+{
+    let value = args
+        .next()
+        .unwrap_or_else(|| panic!("{} requires a value", flag));
+    value
+        .parse()
+        .unwrap_or_else(|e| panic!("invalid value for {}: {}", flag, e))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut path = None;
+    let mut preset = Preset::default();
+    let mut audio = AudioSettings::default();
+    let mut wav = None;
+    let mut mute = false;
+    let mut headless = false;
+    let mut cycles = None;
+    let mut frames = None;
+    let mut keys = None;
+    let mut screenshot = None;
+    let mut registers = None;
+    let mut debug = false;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut rewind = RewindSettings::default();
+    let mut seed = None;
+    let mut record = None;
+    let mut play = None;
+    let mut scale = Scale::Integer;
+    let mut fullscreen = false;
+    let mut paused = false;
+    let mut filter = FilterSettings::default();
+    let mut timing = Timing::default();
+    let mut instructions_per_frame = None;
+    let mut display_wait = None;
+    let mut key_release = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quirks" => preset = flag_value(&mut args, "--quirks"),
+            "--timing" => timing = flag_value(&mut args, "--timing"),
+            "--ipf" => {
+                let value = flag_value(&mut args, "--ipf");
+                if !is_valid_speed(value) {
+                    panic!("invalid value for --ipf: it has to be more than 0");
+                }
+                instructions_per_frame = Some(value);
+            }
+            "--display-wait" => display_wait = Some(flag_value(&mut args, "--display-wait")),
+            "--key-release" => key_release = Some(flag_value(&mut args, "--key-release")),
+            "--frequency" => audio.frequency = flag_value(&mut args, "--frequency"),
+            "--volume" => audio.volume = flag_value(&mut args, "--volume"),
+            "--waveform" => audio.waveform = flag_value(&mut args, "--waveform"),
+            "--wav" => wav = Some(flag_value(&mut args, "--wav")),
+            "--mute" => mute = true,
+            "--headless" => headless = true,
+            "--cycles" => cycles = Some(flag_value(&mut args, "--cycles")),
+            "--frames" => frames = Some(flag_value(&mut args, "--frames")),
+            "--keys" => keys = Some(flag_value(&mut args, "--keys")),
+            "--screenshot" => screenshot = Some(flag_value(&mut args, "--screenshot")),
+            "--registers" => registers = Some(flag_value(&mut args, "--registers")),
+            "--debug" => debug = true,
+            "--load-state" => load_state = Some(flag_value(&mut args, "--load-state")),
+            "--save-state" => save_state = Some(flag_value(&mut args, "--save-state")),
+            "--seed" => seed = Some(flag_value(&mut args, "--seed")),
+            "--record" => record = Some(flag_value(&mut args, "--record")),
+            "--play" => play = Some(flag_value(&mut args, "--play")),
+            "--scale" => scale = flag_value(&mut args, "--scale"),
+            "--fullscreen" => fullscreen = true,
+            "--paused" => paused = true,
+            "--filter" => filter.mode = flag_value(&mut args, "--filter"),
+            "--filter-strength" => filter.strength = flag_value(&mut args, "--filter-strength"),
+            "--rewind-length" => rewind.length = flag_value(&mut args, "--rewind-length"),
+            "--rewind-interval" => rewind.interval = flag_value(&mut args, "--rewind-interval"),
+            _ => path = Some(arg),
+        }
     }
-    window.draw_2d(event, |ctx, graphics, _| {
-        // default screen to black
-        clear(color::BLACK, graphics);
-        // iterate through display buffer for active pixels
-        for x in 0..DISPLAY_WIDTH {
-            for y in 0..DISPLAY_HEIGHT {
-                let index = x + DISPLAY_WIDTH * y;
-                if display.buffer[index] == 1 {
-                    rectangle(
-                        color::WHITE,
-                        [x as f64 * 10.0, y as f64 * 10.0, 10.0, 10.0],
-                        ctx.transform,
-                        graphics,
-                    );
+
+    Options {
+        path: path.expect("you must provide a valid ROM for the emulator"),
+        preset,
+        audio,
+        wav,
+        mute,
+        headless,
+        cycles,
+        frames,
+        keys,
+        screenshot,
+        registers,
+        debug,
+        load_state,
+        save_state,
+        rewind,
+        seed,
+        record,
+        play,
+        scale,
+        fullscreen,
+        paused,
+        filter,
+        timing,
+        instructions_per_frame,
+        display_wait,
+        key_release,
+    }
+}
+
+fn audio_sink(options: &Options) -> Box<dyn AudioSink> {
+    if let Some(path) = &options.wav {
+        return Box::new(WavSink::new(path, options.audio));
+    }
+    // there's nobody to listen to a headless run
+    if options.mute || options.headless {
+        return Box::new(NullSink);
+    }
+    system_audio_sink(options.audio)
+}
+
+#[cfg(feature = "system-audio")]
+fn system_audio_sink(settings: AudioSettings) -> Box<dyn AudioSink> {
+    match rusty_chip8::audio::SystemSink::new(settings) {
+        Ok(sink) => Box::new(sink),
+        Err(e) => {
+            eprintln!("warning: couldn't open the audio device: {}", e);
+            Box::new(NullSink)
+        }
+    }
+}
+
+#[cfg(not(feature = "system-audio"))]
+fn system_audio_sink(_settings: AudioSettings) -> Box<dyn AudioSink> {
+    Box::new(SilentSink { warned: false })
+}
+
+// stands in for the system audio in builds without it, and says so the first
+// time the program beeps instead of staying silent without a word
+#[cfg(not(feature = "system-audio"))]
+struct SilentSink {
+    warned: bool,
+}
+
+#[cfg(not(feature = "system-audio"))]
+impl AudioSink for SilentSink {
+    fn frame(&mut self, sound: rusty_chip8::audio::Sound) {
+        if sound.active && !self.warned {
+            eprintln!(
+                "warning: the program is beeping, but this build has no sound \
+                 (rebuild with the default features, or record it with `--wav <file>`)"
+            );
+            self.warned = true;
+        }
+    }
+}
+
+// the size of a CHIP-8 pixel and the top left corner of the picture, in window coordinates
+fn screen_layout(display: &Display, scale: Scale, viewport: &Viewport) -> (f64, [f64; 2]) {
+    let [window_width, window_height] = viewport.window_size;
+    let (width, height) = (display.width() as f64, display.height() as f64);
+
+    let size = match scale {
+        Scale::Fit => (window_width / width).min(window_height / height),
+        // scale the high resolution picture so low resolution pixels are whole
+        // multiples too, and count in physical pixels for HiDPI screens
+        Scale::Integer => {
+            let dpi = viewport.draw_size[0] as f64 / window_width;
+            let hires = (window_width * dpi / HIRES_DISPLAY_WIDTH as f64)
+                .min(window_height * dpi / HIRES_DISPLAY_HEIGHT as f64)
+                .floor()
+                .max(1.0);
+            hires * HIRES_DISPLAY_WIDTH as f64 / width / dpi
+        }
+    };
+    let origin = [
+        ((window_width - width * size) / 2.0).floor(),
+        ((window_height - height * size) / 2.0).floor(),
+    ];
+    (size, origin)
+}
+
+// the picture lives in a texture, only the rows that changed are uploaded again
+struct Screen {
+    texture: G2dTexture,
+    context: G2dTextureContext,
+    pixels: Vec<u8>, // RGBA, a row is always as wide as the high resolution screen
+}
+
+impl Screen {
+    fn new(window: &mut PistonWindow) -> Screen {
+        let mut context = window.create_texture_context();
+        let pixels = vec![0; BUFFER_SIZE * 4];
+        let settings = TextureSettings::new().filter(piston_window::Filter::Nearest);
+        let size = [HIRES_DISPLAY_WIDTH as u32, HIRES_DISPLAY_HEIGHT as u32];
+        let texture = Texture::create(&mut context, Format::Rgba8, &pixels, size, &settings)
+            .expect("couldn't create the screen texture");
+        Screen {
+            texture,
+            context,
+            pixels,
+        }
+    }
+
+    fn update(&mut self, display: &Display, filter: &Filter, palette: &Palette) {
+        // filtered pixels keep changing after the display stops
+        let rows: Vec<usize> = if filter.is_enabled() {
+            (0..display.height()).collect()
+        } else {
+            display.dirty_rows().collect()
+        };
+        let (first, last) = match (rows.first(), rows.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return,
+        };
+
+        for y in rows {
+            for x in 0..display.width() {
+                let color = filter
+                    .color(display, display.index_of(x, y), palette)
+                    .unwrap_or_else(|| palette.color(0));
+                let offset = (x + y * HIRES_DISPLAY_WIDTH) * 4;
+                for (byte, component) in self.pixels[offset..offset + 4].iter_mut().zip(color) {
+                    *byte = (component * 255.0).round() as u8;
                 }
             }
         }
+
+        // upload the band between the first and last changed row in one go
+        let row_size = HIRES_DISPLAY_WIDTH * 4;
+        let band = &self.pixels[first * row_size..(last + 1) * row_size];
+        let offset = [0, first as u32];
+        let size = [HIRES_DISPLAY_WIDTH as u32, (last - first + 1) as u32];
+        // the trait method, `Texture::update` takes a whole image
+        UpdateTexture::update(
+            &mut self.texture,
+            &mut self.context,
+            Format::Rgba8,
+            band,
+            offset,
+            size,
+        )
+        .expect("couldn't update the screen texture");
+    }
+
+    fn draw(&mut self, display: &Display, scale: Scale, window: &mut PistonWindow, event: &Event) {
+        let width = display.width() as f64;
+        let height = display.height() as f64;
+
+        window.draw_2d(event, |ctx, graphics, device| {
+            self.context.encoder.flush(device);
+            let viewport = match ctx.viewport {
+                Some(viewport) => viewport,
+                None => return,
+            };
+            let (size, [left, top]) = screen_layout(display, scale, &viewport);
+
+            // letterbox around the picture
+            clear(color::BLACK, graphics);
+            Image::new()
+                .src_rect([0.0, 0.0, width, height])
+                .rect([left, top, width * size, height * size])
+                .draw(&self.texture, &ctx.draw_state, ctx.transform, graphics);
+        });
+    }
+}
+
+// switch between a window and borderless fullscreen on the current monitor
+fn toggle_fullscreen(window: &mut PistonWindow) {
+    let window = window.window.ctx.window();
+    let fullscreen = match window.fullscreen() {
+        Some(_) => None,
+        None => Some(Fullscreen::Borderless(window.current_monitor())),
+    };
+    window.set_fullscreen(fullscreen);
+}
+
+// read debugger commands from the terminal without blocking the window
+fn spawn_console() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
     });
+    receiver
 }
 
-fn main() {
-    // open file containing the chip8 ROM
-    let path = env::args()
-        .nth(1)
-        .expect("you must provide a valid ROM for the emulator");
+fn prompt() {
+    print!("(chip8) ");
+    io::stdout().flush().ok();
+}
 
-    let mut file = File::open(path).expect("an error occurred opening the file");
+// read a save state in either format
+fn load_state(chip8: &mut Chip8, path: &str) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let state = SaveState::from_bytes(&data).map_err(|e| format!("{}: {}", path, e))?;
+    chip8
+        .load_state(&state)
+        .map_err(|e| format!("{}: {}", path, e))
+}
 
-    // get data into a u8 array
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
+// run one frame with the debugger watching, returns whether the frame finished
+// (a stop leaves it unfinished, it carries on after resuming)
+fn debug_frame(
+    chip8: &mut Chip8,
+    debugger: &mut Debugger,
+    sink: &mut dyn AudioSink,
+    rewind: &mut Rewind,
+    filter: &mut Filter,
+    console: bool,
+) -> Result<bool, Chip8Error> {
+    match debugger.run_frame(chip8) {
+        Some(StopReason::Fault(e)) => return Err(e),
+        Some(reason) => println!("\n{}", reason),
+        None => {}
+    }
+    if debugger.is_paused() {
+        if console {
+            print!("{}", Debugger::location(chip8));
+            prompt();
+        }
+        return Ok(false);
+    }
+
+    sink.frame(chip8.sound());
+    rewind.record(chip8);
+    filter.record(chip8.display());
+    Ok(true)
+}
 
-    // create piston window instance
-    let mut window: PistonWindow = WindowSettings::new("Chip 8 - Emulator", (640, 320))
+// the speed and whether the machine is paused or fast-forwarding, or what went wrong
+fn window_title(chip8: &Chip8, paused: bool, fast_forward: bool, fault: Option<&str>) -> String {
+    if let Some(fault) = fault {
+        return format!("Chip 8 - Emulator ({})", fault);
+    }
+    let mut status = match chip8.quirks().timing {
+        Timing::Fixed => format!("{} ipf", chip8.instructions_per_frame()),
+        Timing::Vip => "VIP timing".to_string(),
+    };
+    if paused {
+        status.push_str(", paused");
+    } else if fast_forward {
+        status.push_str(", fast forward");
+    }
+    format!("Chip 8 - Emulator ({})", status)
+}
+
+// the next speed up or down from the current one, in instructions per frame
+fn next_speed(current: f64, faster: bool) -> f64 {
+    let next = if faster {
+        SPEEDS.iter().find(|&&speed| speed > current)
+    } else {
+        SPEEDS.iter().rev().find(|&&speed| speed < current)
+    };
+    // never below the slowest step, whatever speed it started at
+    next.copied().unwrap_or(current).max(SPEEDS[0])
+}
+
+// run one movie frame, desyncs are reported but playback goes on
+fn movie_frame(
+    chip8: &mut Chip8,
+    session: &mut MovieSession,
+    sink: &mut dyn AudioSink,
+    filter: &mut Filter,
+) -> Result<(), Chip8Error> {
+    if let Some(desync) = session.run_frame(chip8)? {
+        eprintln!("warning: {}", desync);
+    }
+    sink.frame(chip8.sound());
+    filter.record(chip8.display());
+    Ok(())
+}
+
+fn run_windowed(
+    chip8: &mut Chip8,
+    sink: &mut dyn AudioSink,
+    movie: &mut Option<MovieSession>,
+    options: &Options,
+) {
+    let bindings = Bindings::load(&options.path).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    });
+
+    // create piston window instance, 10 window pixels per CHIP-8 pixel to start with
+    let size = ((DISPLAY_WIDTH * 10) as u32, (DISPLAY_HEIGHT * 10) as u32);
+    let mut window: PistonWindow = WindowSettings::new("Chip 8 - Emulator", size)
         .exit_on_esc(true)
+        .fullscreen(options.fullscreen)
         .build()
         .unwrap();
+    let mut screen = Screen::new(&mut window);
+
+    let mut debugger = Debugger::new();
+    let mut fault = None;
+    let mut title = String::new();
+    let mut tracker = bindings.tracker();
+    let gamepads = Gamepads::open();
+    let mut shift = false;
+    let mut rewind = Rewind::new(options.rewind);
+    let mut filter = Filter::new(options.filter);
+    let mut rewinding = false;
+    let mut movie_paused = options.paused;
+    let mut fast_forward = false;
+    let mut scheduler = Scheduler::new();
+
+    // the hotkey cycles through the built-in palettes, and the configured one if it isn't built in
+    let mut palettes = Palette::presets();
+    let mut palette = match palettes.iter().position(|p| p == bindings.palette()) {
+        Some(index) => index,
+        None => {
+            palettes.insert(0, bindings.palette().clone());
+            0
+        }
+    };
 
-    let mut chip8 = Chip8::new(data);
-    let mut chip8_time = 0.0;
+    if options.paused && movie.is_none() {
+        debugger.pause();
+    }
+
+    // start paused so breakpoints can be set before the program runs
+    let console = options.debug.then(|| {
+        debugger.pause();
+        print!("{}", Debugger::location(chip8));
+        prompt();
+        spawn_console()
+    });
 
     while let Some(e) = window.next() {
+        if let Some(console) = &console {
+            while let Ok(line) = console.try_recv() {
+                match debugger.execute(chip8, &line) {
+                    Ok(output) => print!("{}", output),
+                    Err(e) => println!("error: {}", e),
+                }
+                prompt();
+            }
+        }
+
         // draw to screen updates
-        if let Some(_) = e.render_args() {
-            draw_screen(&mut chip8.display, &mut window, &e);
+        if e.render_args().is_some() {
+            screen.update(chip8.display(), &filter, &palettes[palette]);
+            chip8.display_mut().mark_clean();
+            screen.draw(chip8.display(), options.scale, &mut window, &e);
+        }
+
+        // the program asked the interpreter to exit (00FD)
+        if chip8.is_halted() {
+            window.set_should_close(true);
         }
 
         // game state updates
         if let Some(u) = e.update_args() {
-            // run next cycle and keep track of delta time
-            chip8.cycle(u.dt);
-            chip8_time += u.dt;
-            // // at 60 Hz decrement chip8's timers
-            if chip8_time > 1.0 / 60.0 {
-                chip8.decrement_timers();
-                chip8_time -= 1.0 / 60.0;
+            // the machine stays frozen while paused so the screen can be inspected
+            if debugger.is_paused() {
+                continue;
+            }
+            // fast-forwarding runs as many frames as fit until the next update,
+            // and only the last one before a render gets drawn
+            let frames = scheduler.frames(u.dt);
+            let deadline = Instant::now() + Duration::from_secs_f64(u.dt);
+            let fast_forwarding = fast_forward && !rewinding;
+            let frames = (0..).take_while(|&frame| match fast_forwarding {
+                true => Instant::now() < deadline,
+                false => frame < frames,
+            });
+
+            // holding backspace steps back through the snapshots instead, one per frame
+            if rewinding {
+                for _ in frames {
+                    rewind.rewind(chip8);
+                    sink.frame(chip8.sound());
+                    filter.record(chip8.display());
+                }
+                continue;
             }
-        }
 
-        // event for key press
-        if let Some(Button::Keyboard(keycode)) = e.press_args() {
-            if let Some(key) = from_key_code(keycode) {
-                chip8.handle_key_down(key);
+            // movies run whole frames so they replay exactly
+            if let Some(session) = movie.as_mut() {
+                if session.is_finished() && !movie_paused {
+                    println!("movie finished at frame {}", session.frame());
+                    movie_paused = true;
+                }
+                if movie_paused {
+                    continue;
+                }
+                fault = None;
+                for _ in frames {
+                    if session.is_finished() {
+                        break;
+                    }
+                    if let Err(e) = movie_frame(chip8, session, sink, &mut filter) {
+                        eprintln!("error: {}", e);
+                        fault = Some(e.to_string());
+                        movie_paused = true;
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            fault = None;
+            for _ in frames {
+                match debug_frame(
+                    chip8,
+                    &mut debugger,
+                    sink,
+                    &mut rewind,
+                    &mut filter,
+                    console.is_some(),
+                ) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        eprintln!("error: {}", e);
+                        fault = Some(e.to_string());
+                        break;
+                    }
+                }
             }
         }
 
-        // event for key release
-        if let Some(Button::Keyboard(keycode)) = e.release_args() {
-            if let Some(key) = from_key_code(keycode) {
-                chip8.handle_key_up(key);
+        // keys, controller buttons, the hat and the sticks all go through the bindings
+        let mut inputs = tracker.events(&e);
+        for event in gamepads.events() {
+            inputs.extend(tracker.gamepad_events(event));
+        }
+        for (input, pressed) in inputs {
+            for key in bindings.chip8_keys(input) {
+                match movie.as_mut() {
+                    Some(session) => session.key(chip8, key, pressed),
+                    None if pressed => chip8.handle_key_down(key),
+                    None => chip8.handle_key_up(key),
+                }
+            }
+            if input == Input::Key(Key::LShift) || input == Input::Key(Key::RShift) {
+                shift = pressed;
+            }
+            if !pressed {
+                if bindings.is_bound(Hotkey::Rewind, input) {
+                    rewinding = false;
+                }
+                if bindings.is_bound(Hotkey::FastForward, input) {
+                    fast_forward = false;
+                }
+                continue;
+            }
+
+            // save states, resets and rewinding would break a movie's sync
+            match (bindings.hotkey(input, shift), movie.as_mut()) {
+                (Some(Hotkey::Pause), Some(_)) => movie_paused = !movie_paused,
+                (Some(Hotkey::Pause), None) if debugger.is_paused() => debugger.resume(),
+                (Some(Hotkey::Pause), None) => debugger.pause(),
+                // step one frame at a time while paused
+                (Some(Hotkey::FrameAdvance), Some(session))
+                    if movie_paused && !session.is_finished() =>
+                {
+                    if let Err(e) = movie_frame(chip8, session, sink, &mut filter) {
+                        eprintln!("error: {}", e);
+                    }
+                }
+                (Some(Hotkey::FrameAdvance), None) if debugger.is_paused() => {
+                    // finishes the frame a stop cut short, or runs the next one
+                    debugger.resume();
+                    let console = console.is_some();
+                    let frame = debug_frame(
+                        chip8,
+                        &mut debugger,
+                        sink,
+                        &mut rewind,
+                        &mut filter,
+                        console,
+                    );
+                    if let Err(e) = frame {
+                        eprintln!("error: {}", e);
+                        fault = Some(e.to_string());
+                    }
+                    debugger.pause();
+                }
+                (Some(Hotkey::FastForward), _) => fast_forward = true,
+                // changing the speed would break a movie's sync, and VIP timing has its own
+                (Some(hotkey @ (Hotkey::SpeedUp | Hotkey::SpeedDown)), None)
+                    if chip8.quirks().timing == Timing::Fixed =>
+                {
+                    let speed =
+                        next_speed(chip8.instructions_per_frame(), hotkey == Hotkey::SpeedUp);
+                    match chip8.set_instructions_per_frame(speed) {
+                        Ok(()) => println!("speed: {} instructions per frame", speed),
+                        Err(e) => eprintln!("warning: {}", e),
+                    }
+                }
+                // take over the playback and record from this frame on
+                (Some(Hotkey::Rerecord), Some(session))
+                    if !session.is_recording() && options.record.is_some() =>
+                {
+                    session.rerecord(chip8);
+                    movie_paused = false;
+                    println!("recording from frame {}", session.frame());
+                }
+                (Some(Hotkey::Palette), _) => {
+                    palette = (palette + 1) % palettes.len();
+                    chip8.display_mut().mark_dirty();
+                    println!("palette: {}", palettes[palette].name);
+                }
+                (Some(Hotkey::Fullscreen), _) => toggle_fullscreen(&mut window),
+                (Some(Hotkey::Reset), None) => {
+                    chip8.reset();
+                    rewind.clear();
+                    filter.clear();
+                }
+                (Some(Hotkey::Rewind), None) => {
+                    rewinding = true;
+                }
+                (Some(Hotkey::SaveState(slot)), None) => {
+                    let path = format!("{}.state{}", options.path, slot);
+                    match fs::write(&path, chip8.save_state().to_bytes()) {
+                        Ok(()) => println!("saved state to {}", path),
+                        Err(e) => eprintln!("error: {}: {}", path, e),
+                    }
+                }
+                (Some(Hotkey::LoadState(slot)), None) => {
+                    let path = format!("{}.state{}", options.path, slot);
+                    match load_state(chip8, &path) {
+                        Ok(()) => {
+                            rewind.clear();
+                            filter.clear();
+                            println!("loaded state from {}", path);
+                        }
+                        Err(e) => eprintln!("error: {}", e),
+                    }
+                }
+                _ => {}
             }
         }
+
+        let paused = match movie {
+            Some(_) => movie_paused,
+            None => debugger.is_paused(),
+        };
+        let current = window_title(chip8, paused, fast_forward, fault.as_deref());
+        if current != title {
+            window.set_title(current.clone());
+            title = current;
+        }
+    }
+}
+
+// play a whole movie back, returns whether it stayed in sync
+fn play_movie(
+    chip8: &mut Chip8,
+    session: &mut MovieSession,
+    sink: &mut dyn AudioSink,
+) -> Result<bool, Chip8Error> {
+    let mut in_sync = true;
+    while !session.is_finished() {
+        if let Some(desync) = session.run_frame(chip8)? {
+            eprintln!("error: {}", desync);
+            in_sync = false;
+        }
+        sink.frame(chip8.sound());
+    }
+    Ok(in_sync)
+}
+
+fn run_headless_mode(
+    chip8: &mut Chip8,
+    sink: &mut dyn AudioSink,
+    movie: &mut Option<MovieSession>,
+    options: &Options,
+) -> bool {
+    let script = match &options.keys {
+        Some(path) => {
+            let source = fs::read_to_string(path).expect("couldn't read the key script");
+            KeyScript::parse(&source).unwrap_or_else(|e| {
+                eprintln!("error: {}: {}", path, e);
+                process::exit(1);
+            })
+        }
+        None => KeyScript::default(),
+    };
+
+    let length = match (options.cycles, options.frames) {
+        (Some(cycles), _) => RunLength::Cycles(cycles),
+        (None, Some(frames)) => RunLength::Frames(frames),
+        (None, None) => RunLength::Frames(60),
+    };
+
+    // a movie brings its own input and length
+    let result = match movie {
+        Some(session) => play_movie(chip8, session, sink),
+        None => run_headless(chip8, length, &script, sink).map(|_| true),
+    };
+    let success = match result {
+        Ok(success) => success,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            false
+        }
+    };
+
+    // dump the final state even after a fault, that's when it's most useful
+    match options.screenshot.as_deref() {
+        Some("-") => print!("{}", framebuffer_ascii(chip8.display())),
+        Some(path) if path.ends_with(".png") => {
+            let file = File::create(path).expect("couldn't create the screenshot");
+            // the default colors, so screenshots don't depend on the configuration
+            framebuffer_png(chip8.display(), &Palette::default(), BufWriter::new(file))
+                .expect("couldn't write the screenshot");
+        }
+        Some(path) => {
+            fs::write(path, framebuffer_ascii(chip8.display()))
+                .expect("couldn't write the screenshot");
+        }
+        None => {}
+    }
+    match options.registers.as_deref() {
+        Some("-") => println!("{}", registers_json(chip8)),
+        Some(path) => {
+            fs::write(path, registers_json(chip8)).expect("couldn't write the registers");
+        }
+        None => {}
+    }
+
+    success
+}
+
+// `disasm [--syntax cowgod|octo] <rom>` prints a listing of the program
+fn disasm_command<I: Iterator<Item = String>>(mut args: I) {
+    let mut syntax = Syntax::default();
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => syntax = flag_value(&mut args, "--syntax"),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.expect("you must provide a ROM to disassemble");
+    let rom = fs::read(path).expect("an error occurred opening the file");
+    print!("{}", disassemble_rom(&rom).render(syntax));
+}
+
+// the assembler syntax of a source file, based on its extension
+fn source_syntax(path: &str) -> Option<Syntax> {
+    match Path::new(path).extension()?.to_str()? {
+        "8o" => Some(Syntax::Octo),
+        "asm" => Some(Syntax::Cowgod),
+        _ => None,
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("disasm") {
+        args.next();
+        disasm_command(args);
+        return;
+    }
+
+    let mut options = parse_args(args);
+
+    // movies replay with the settings they were recorded with
+    let movie = options.play.as_ref().map(|path| {
+        Movie::load(path).unwrap_or_else(|e| {
+            eprintln!("error: {}: {}", path, e);
+            process::exit(1);
+        })
+    });
+    if let Some(movie) = &movie {
+        options.preset = movie.preset;
+        options.timing = movie.timing;
+        options.display_wait = Some(movie.display_wait);
+        options.key_release = Some(movie.key_release);
+        options.instructions_per_frame = Some(movie.instructions_per_frame);
+    }
+
+    // open file containing the chip8 ROM
+    let mut file = File::open(&options.path).expect("an error occurred opening the file");
+
+    // get data into a u8 array
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).unwrap();
+
+    // source files get assembled before they run
+    if let Some(syntax) = source_syntax(&options.path) {
+        let source = String::from_utf8_lossy(&data);
+        data = assemble(&source, syntax).unwrap_or_else(|e| {
+            eprintln!("{}:{}", options.path, e);
+            process::exit(1);
+        });
+    }
+
+    let preset = Quirks::new(options.preset);
+    let quirks = Quirks {
+        timing: options.timing,
+        display_wait: options.display_wait.unwrap_or(preset.display_wait),
+        key_release: options.key_release.unwrap_or(preset.key_release),
+        ..preset
+    };
+    let mut chip8 = Chip8::new(data.clone(), quirks).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    });
+    if let Some(seed) = options.seed {
+        chip8.set_seed(seed);
+    }
+    if let Some(instructions_per_frame) = options.instructions_per_frame {
+        chip8
+            .set_instructions_per_frame(instructions_per_frame)
+            .unwrap_or_else(|e| {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            });
+    }
+    let mut sink = audio_sink(&options);
+
+    // restore the SUPER-CHIP user flags saved by a previous run
+    let flags_path = format!("{}.flags", options.path);
+    if let Ok(flags) = fs::read(&flags_path) {
+        let mut rpl = [0; 16];
+        let len = flags.len().min(rpl.len());
+        rpl[..len].copy_from_slice(&flags[..len]);
+        chip8.set_rpl_flags(rpl);
+    }
+    if let Some(path) = &options.load_state {
+        load_state(&mut chip8, path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        });
+    }
+    let saved_flags = chip8.rpl_flags();
+
+    // movies start from power-on, whatever state was loaded
+    let mut session = match (movie, &options.record) {
+        (Some(movie), _) => Some(MovieSession::play(&mut chip8, movie, &data).unwrap_or_else(
+            |e| {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            },
+        )),
+        (None, Some(_)) if options.headless => {
+            eprintln!("error: --record needs a window, headless runs can only --play movies");
+            process::exit(1);
+        }
+        (None, Some(_)) => Some(MovieSession::record(&mut chip8, &data, options.preset)),
+        (None, None) => None,
+    };
+
+    let success = if options.headless {
+        run_headless_mode(&mut chip8, sink.as_mut(), &mut session, &options)
+    } else {
+        run_windowed(&mut chip8, sink.as_mut(), &mut session, &options);
+        true
+    };
+
+    if let (Some(session), Some(path)) = (&session, &options.record) {
+        if session.is_recording() {
+            session
+                .movie()
+                .save(path)
+                .expect("couldn't write the movie");
+        }
+    }
+
+    // `.json` states are for reading, everything else gets the compact format
+    if let Some(path) = &options.save_state {
+        let state = chip8.save_state();
+        let data = if path.ends_with(".json") {
+            state.to_json().into_bytes()
+        } else {
+            state.to_bytes()
+        };
+        fs::write(path, data).expect("couldn't write the save state");
+    }
+
+    if let Err(e) = sink.finish() {
+        eprintln!("error: couldn't write the audio output: {}", e);
+    }
+
+    // persist the user flags so they survive between runs (a movie's flags aren't the user's)
+    if chip8.rpl_flags() != saved_flags && options.play.is_none() {
+        fs::write(&flags_path, chip8.rpl_flags()).expect("couldn't save the user flags");
+    }
+
+    if !success {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_hotkeys_stay_above_zero() {
+        assert_eq!(next_speed(1.0, false), 1.0);
+        assert_eq!(next_speed(0.5, false), 1.0);
+        assert_eq!(next_speed(10.0, false), 7.0);
+        assert_eq!(next_speed(10.0, true), 15.0);
+        assert_eq!(next_speed(1000.0, true), 1000.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "--ipf")]
+    fn rejects_zero_instructions_per_frame() {
+        let args = ["--ipf", "0", "rom.ch8"].iter().map(|arg| arg.to_string());
+        parse_args(args);
     }
 }
@@ -0,0 +1,366 @@
+use crate::chip8::{is_valid_speed, Chip8};
+use crate::error::Chip8Error;
+use crate::headless::KeyEvent;
+use crate::quirks::{Preset, Timing};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// bumped whenever the layout of `Movie` changes, older movies can't be played
+// back since they lack settings that playback depends on
+pub const MOVIE_VERSION: u32 = 2;
+
+// how often the machine state is hashed to detect desyncs
+pub const CHECKSUM_INTERVAL: usize = 60;
+
+// the inputs of a run from power-on, enough to replay it exactly
+//
+// the random numbers are stored too: the seeded generator would produce them
+// again, but only as long as `rand` keeps turning its output into bytes the same way
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Movie {
+    pub version: u32,
+    pub rom_hash: String,
+    pub preset: Preset,
+    pub timing: Timing,
+    pub display_wait: bool,
+    pub key_release: bool,
+    pub instructions_per_frame: f64,
+    pub seed: u64,
+    pub flags: [u8; 16], // SUPER-CHIP user flags at power-on
+    pub frames: usize,
+    pub events: Vec<KeyEvent>,
+    pub random: Vec<u8>, // every Cxkk result, in order
+    pub checksums: Vec<Checksum>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub frame: usize,
+    pub hash: String,
+}
+
+// playback no longer matches the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: String,
+    pub found: String,
+}
+
+// records a movie or plays one back, one frame at a time
+pub struct MovieSession {
+    movie: Movie,
+    frame: usize,
+    recording: bool,
+    next_event: usize,
+}
+
+impl Movie {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Movie> {
+        let movie: Movie = serde_json::from_slice(&fs::read(path)?)?;
+        if movie.version != MOVIE_VERSION {
+            return Err(io::Error::other(format!(
+                "movie version {} isn't supported (expected {})",
+                movie.version, MOVIE_VERSION
+            )));
+        }
+        if let Some(event) = movie.events.iter().find(|event| event.key >= 16) {
+            return Err(io::Error::other(format!(
+                "invalid key {} in the movie (keys go from 0 to F)",
+                event.key
+            )));
+        }
+        let in_order = movie
+            .events
+            .windows(2)
+            .all(|pair| pair[0].frame <= pair[1].frame);
+        if !in_order {
+            return Err(io::Error::other(
+                "the movie's key events are out of frame order",
+            ));
+        }
+        if !is_valid_speed(movie.instructions_per_frame) {
+            return Err(io::Error::other(format!(
+                "invalid instructions per frame {} in the movie",
+                movie.instructions_per_frame
+            )));
+        }
+        Ok(movie)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("movies always serialize");
+        fs::write(path, json)
+    }
+}
+
+impl MovieSession {
+    // restart the program and record from power-on
+    pub fn record(chip8: &mut Chip8, rom: &[u8], preset: Preset) -> MovieSession {
+        chip8.reset();
+        chip8.log_random();
+        MovieSession {
+            movie: Movie {
+                version: MOVIE_VERSION,
+                rom_hash: hash(rom),
+                preset,
+                timing: chip8.quirks().timing,
+                display_wait: chip8.quirks().display_wait,
+                key_release: chip8.quirks().key_release,
+                instructions_per_frame: chip8.instructions_per_frame(),
+                seed: chip8.seed(),
+                flags: chip8.rpl_flags(),
+                frames: 0,
+                events: Vec::new(),
+                random: Vec::new(),
+                checksums: Vec::new(),
+            },
+            frame: 0,
+            recording: true,
+            next_event: 0,
+        }
+    }
+
+    // restart the program the way the movie was recorded, `chip8` has to use
+    // the movie's preset, timing, display wait, key release and speed
+    pub fn play(chip8: &mut Chip8, movie: Movie, rom: &[u8]) -> Result<MovieSession, String> {
+        if movie.rom_hash != hash(rom) {
+            return Err(format!(
+                "the movie was recorded with another ROM (hash {}, loaded {})",
+                movie.rom_hash,
+                hash(rom)
+            ));
+        }
+
+        chip8.set_seed(movie.seed);
+        chip8.set_rpl_flags(movie.flags);
+        chip8.reset();
+        chip8.replay_random(&movie.random);
+        Ok(MovieSession {
+            movie,
+            frame: 0,
+            recording: false,
+            next_event: 0,
+        })
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.recording && self.frame >= self.movie.frames
+    }
+
+    // live input, only reaches the machine while recording
+    pub fn key(&mut self, chip8: &mut Chip8, key: usize, pressed: bool) {
+        if !self.recording {
+            return;
+        }
+        self.movie.events.push(KeyEvent {
+            frame: self.frame,
+            key,
+            pressed,
+        });
+        if pressed {
+            chip8.handle_key_down(key);
+        } else {
+            chip8.handle_key_up(key);
+        }
+    }
+
+    // take over a playback and record from the current frame on
+    pub fn rerecord(&mut self, chip8: &mut Chip8) {
+        let frame = self.frame;
+        self.movie.events.retain(|event| event.frame < frame);
+        let used = self.movie.random.len() - chip8.stop_random_replay();
+        self.movie.random.truncate(used);
+        chip8.log_random();
+        self.movie
+            .checksums
+            .retain(|checksum| checksum.frame <= frame);
+        self.movie.frames = frame;
+        self.recording = true;
+    }
+
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Result<Option<Desync>, Chip8Error> {
+        if !self.recording {
+            while let Some(event) = self.movie.events.get(self.next_event) {
+                if event.frame > self.frame {
+                    break;
+                }
+                if event.pressed {
+                    chip8.handle_key_down(event.key);
+                } else {
+                    chip8.handle_key_up(event.key);
+                }
+                self.next_event += 1;
+            }
+        }
+
+        chip8.run_frame()?;
+        self.frame += 1;
+
+        if self.recording {
+            self.movie.frames = self.frame;
+            self.movie.random.extend(chip8.take_random_log());
+        }
+        if !self.frame.is_multiple_of(CHECKSUM_INTERVAL) {
+            return Ok(None);
+        }
+
+        let found = hash(&chip8.save_state().to_bytes());
+        if self.recording {
+            self.movie.checksums.push(Checksum {
+                frame: self.frame,
+                hash: found,
+            });
+            return Ok(None);
+        }
+
+        let checksums = &self.movie.checksums;
+        let desync = checksums
+            .binary_search_by_key(&self.frame, |checksum| checksum.frame)
+            .ok()
+            .map(|index| &checksums[index])
+            .filter(|checksum| checksum.hash != found)
+            .map(|checksum| Desync {
+                frame: self.frame,
+                expected: checksum.hash.clone(),
+                found,
+            });
+        Ok(desync)
+    }
+}
+
+// 64 bit FNV-1a, stable across platforms and Rust versions unlike `DefaultHasher`
+pub fn hash(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "movie desynced at frame {} (state hash {}, expected {})",
+            self.frame, self.found, self.expected
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    // V0 = random, forever
+    const ROM: [u8; 4] = [0xC0, 0xFF, 0x12, 0x00];
+
+    fn chip8() -> Chip8 {
+        Chip8::new(ROM.to_vec(), Quirks::new(Preset::Vip)).unwrap()
+    }
+
+    #[test]
+    fn records_every_random_number() {
+        let mut chip8 = chip8();
+        let mut session = MovieSession::record(&mut chip8, &ROM, Preset::Vip);
+        for _ in 0..3 {
+            session.run_frame(&mut chip8).unwrap();
+        }
+        let random = &session.movie().random;
+        assert!(!random.is_empty());
+        assert_eq!(random.last(), Some(&chip8.read_register(0)));
+    }
+
+    #[test]
+    fn plays_back_the_recorded_random_numbers() {
+        let mut recorder = chip8();
+        let mut session = MovieSession::record(&mut recorder, &ROM, Preset::Vip);
+        for _ in 0..3 {
+            session.run_frame(&mut recorder).unwrap();
+        }
+        let mut movie = session.movie().clone();
+        // numbers the generator would never give in a row
+        for (index, value) in movie.random.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+        let last = movie.random.len() - 1;
+
+        let mut player = chip8();
+        let mut session = MovieSession::play(&mut player, movie, &ROM).unwrap();
+        while !session.is_finished() {
+            session.run_frame(&mut player).unwrap();
+        }
+        assert_eq!(player.read_register(0), last as u8);
+    }
+
+    #[test]
+    fn rerecord_drops_the_numbers_not_played_yet() {
+        let mut recorder = chip8();
+        let mut session = MovieSession::record(&mut recorder, &ROM, Preset::Vip);
+        session.run_frame(&mut recorder).unwrap();
+        let first_frame = session.movie().random.clone();
+        session.run_frame(&mut recorder).unwrap();
+
+        let mut player = chip8();
+        let movie = session.movie().clone();
+        let mut session = MovieSession::play(&mut player, movie, &ROM).unwrap();
+        session.run_frame(&mut player).unwrap();
+        session.rerecord(&mut player);
+        assert_eq!(session.movie().random, first_frame);
+
+        session.run_frame(&mut player).unwrap();
+        assert_eq!(session.movie().random.len(), first_frame.len() * 2);
+        assert_eq!(
+            session.movie().random.last(),
+            Some(&player.read_register(0))
+        );
+    }
+
+    fn load_edited(edit: impl FnOnce(&mut Movie)) -> io::Result<Movie> {
+        let mut chip8 = chip8();
+        let mut session = MovieSession::record(&mut chip8, &ROM, Preset::Vip);
+        session.key(&mut chip8, 1, true);
+        session.run_frame(&mut chip8).unwrap();
+        session.key(&mut chip8, 1, false);
+        session.run_frame(&mut chip8).unwrap();
+
+        let mut movie = session.movie().clone();
+        edit(&mut movie);
+        let path = std::env::temp_dir().join(format!(
+            "rusty-chip8-movie-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        movie.save(&path).unwrap();
+        let result = Movie::load(&path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn rejects_invalid_events() {
+        assert!(load_edited(|_| ()).is_ok());
+        assert!(load_edited(|movie| movie.events[0].key = 99).is_err());
+        assert!(load_edited(|movie| movie.events.swap(0, 1)).is_err());
+    }
+
+    #[test]
+    fn rejects_older_versions() {
+        assert!(load_edited(|movie| movie.version = 1).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use crate::display::PLANE_COUNT;
+
+// one color for every combination of the XO-CHIP bitplanes: the background,
+// plane 1, plane 2 and both planes overlapping
+pub const PALETTE_SIZE: usize = 1 << PLANE_COUNT;
+
+// RGBA with components from 0.0 to 1.0, the way piston takes them
+pub type Color = [f32; 4];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [Color; PALETTE_SIZE],
+}
+
+// the built-in palettes, the first one is the default
+const PRESETS: [(&str, [&str; PALETTE_SIZE]); 9] = [
+    ("classic", ["#000000", "#ffffff", "#aaaaaa", "#555555"]),
+    ("green", ["#0a1a0a", "#33ff66", "#1f9940", "#a3ffbb"]),
+    ("amber", ["#1a1000", "#ffb000", "#996a00", "#ffd680"]),
+    // the ones Octo ships with
+    ("octo", ["#996600", "#ffcc00", "#ff6600", "#662200"]),
+    ("lcd", ["#f9ffb3", "#3d8026", "#abcc47", "#00131a"]),
+    ("hotdog", ["#000000", "#ff0000", "#ffff00", "#ffffff"]),
+    ("gray", ["#aaaaaa", "#000000", "#ffffff", "#666666"]),
+    ("cga0", ["#000000", "#00ff00", "#ff0000", "#ffff00"]),
+    ("cga1", ["#000000", "#ff00ff", "#00ffff", "#ffffff"]),
+];
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::presets().remove(0)
+    }
+}
+
+impl Palette {
+    pub fn presets() -> Vec<Palette> {
+        PRESETS
+            .iter()
+            .map(|(name, colors)| Palette::from_hex(name, colors).unwrap())
+            .collect()
+    }
+
+    pub fn named(name: &str) -> Option<Palette> {
+        Palette::presets()
+            .into_iter()
+            .find(|palette| palette.name == name)
+    }
+
+    // two colors are enough for programs that only use the first plane, the
+    // other planes are drawn in the foreground color then
+    pub fn from_hex<S: AsRef<str>>(name: &str, colors: &[S]) -> Result<Palette, String> {
+        let parsed = colors
+            .iter()
+            .map(|color| parse_color(color.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let colors = match parsed.len() {
+            2 => [parsed[0], parsed[1], parsed[1], parsed[1]],
+            PALETTE_SIZE => [parsed[0], parsed[1], parsed[2], parsed[3]],
+            n => {
+                return Err(format!(
+                    "a palette needs 2 or {} colors, not {}",
+                    PALETTE_SIZE, n
+                ))
+            }
+        };
+        Ok(Palette {
+            name: name.to_string(),
+            colors,
+        })
+    }
+
+    // the color of a display buffer pixel
+    pub fn color(&self, pixel: u8) -> Color {
+        self.colors[pixel as usize % PALETTE_SIZE]
+    }
+}
+
+// `#rrggbb`, the `#` is optional
+pub fn parse_color(hex: &str) -> Result<Color, String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    let value = Some(digits)
+        .filter(|digits| digits.len() == 6)
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| format!("invalid color `{}` (expected #rrggbb)", hex))?;
+    let component = |shift: u32| ((value >> shift) & 0xff) as f32 / 255.0;
+    Ok([component(16), component(8), component(0), 1.0])
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// how Fx55/Fx65 change the I register after running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexIncrement {
+    Unchanged,  // I is left untouched
+    ByX,        // I = I + x
+    ByXPlusOne, // I = I + x + 1
+}
+
+// how long instructions take to run
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Timing {
+    // every instruction takes the same time, a set number of them per frame
+    #[default]
+    Fixed,
+    // every instruction takes as many 1802 machine cycles as on the COSMAC VIP,
+    // and the timers tick in the VIP's 60Hz display interrupt
+    Vip,
+}
+
+// the ambiguous opcodes that different interpreters disagree on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // how Fx55/Fx65 change I
+    pub index_increment: IndexIncrement,
+    // Bxnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_uses_vx: bool,
+    // 8xy1/8xy2/8xy3 reset VF to zero
+    pub logic_resets_vf: bool,
+    // Dxyn clips sprites at the screen edges instead of wrapping them
+    pub clip_sprites: bool,
+    // Dxy0 draws a 16x16 sprite (SUPER-CHIP) instead of nothing
+    pub large_sprites: bool,
+    // 64KiB address space instead of 4KiB (XO-CHIP)
+    pub extended_memory: bool,
+    // Dxyn waits for the next 60Hz vertical blank, so at most 60 sprites are drawn a second
+    pub display_wait: bool,
+    // Fx0A finishes when the key is released instead of when it's pressed
+    pub key_release: bool,
+    // the timing model, never picked by a preset since most programs expect a fixed speed
+    pub timing: Timing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Vip,    // original COSMAC VIP interpreter
+    Chip48, // HP-48 CHIP-48
+    Schip,  // SUPER-CHIP 1.1
+    XoChip, // Octo's XO-CHIP
+    #[default]
+    Modern, // what most modern emulators (and tutorials) implement
+}
+
+impl Quirks {
+    pub fn new(preset: Preset) -> Quirks {
+        match preset {
+            Preset::Vip => Quirks {
+                shift_uses_vy: true,
+                index_increment: IndexIncrement::ByXPlusOne,
+                jump_uses_vx: false,
+                logic_resets_vf: true,
+                clip_sprites: true,
+                large_sprites: false,
+                extended_memory: false,
+                display_wait: true,
+                key_release: true,
+                timing: Timing::Fixed,
+            },
+            Preset::Chip48 => Quirks {
+                shift_uses_vy: false,
+                index_increment: IndexIncrement::ByX,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                clip_sprites: true,
+                large_sprites: false,
+                extended_memory: false,
+                display_wait: false,
+                key_release: false,
+                timing: Timing::Fixed,
+            },
+            Preset::Schip => Quirks {
+                shift_uses_vy: false,
+                index_increment: IndexIncrement::Unchanged,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                clip_sprites: true,
+                large_sprites: true,
+                extended_memory: false,
+                display_wait: false,
+                key_release: false,
+                timing: Timing::Fixed,
+            },
+            Preset::XoChip => Quirks {
+                shift_uses_vy: true,
+                index_increment: IndexIncrement::ByXPlusOne,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+                clip_sprites: false,
+                large_sprites: true,
+                extended_memory: true,
+                display_wait: false,
+                key_release: true,
+                timing: Timing::Fixed,
+            },
+            Preset::Modern => Quirks {
+                shift_uses_vy: false,
+                index_increment: IndexIncrement::Unchanged,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+                clip_sprites: false,
+                large_sprites: true,
+                extended_memory: false,
+                display_wait: false,
+                key_release: false,
+                timing: Timing::Fixed,
+            },
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::new(Preset::default())
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Preset, String> {
+        match value.to_lowercase().as_str() {
+            "vip" | "chip8" | "chip-8" => Ok(Preset::Vip),
+            "chip48" | "chip-48" => Ok(Preset::Chip48),
+            "schip" | "superchip" | "schip1.1" => Ok(Preset::Schip),
+            "xochip" | "xo-chip" => Ok(Preset::XoChip),
+            "modern" => Ok(Preset::Modern),
+            _ => Err(format!(
+                "unknown quirks preset `{}` (expected vip, chip48, schip, xochip or modern)",
+                value
+            )),
+        }
+    }
+}
+
+impl FromStr for Timing {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Timing, String> {
+        match value.to_lowercase().as_str() {
+            "fixed" => Ok(Timing::Fixed),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(format!(
+                "unknown timing `{}` (expected fixed or vip)",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Preset::Vip => "vip",
+            Preset::Chip48 => "chip48",
+            Preset::Schip => "schip",
+            Preset::XoChip => "xochip",
+            Preset::Modern => "modern",
+        };
+        write!(f, "{}", name)
+    }
+}
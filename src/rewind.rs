@@ -0,0 +1,229 @@
+use crate::chip8::Chip8;
+use crate::state::SaveState;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewindSettings {
+    pub length: f64,     // seconds of gameplay to keep
+    pub interval: usize, // frames between snapshots, rewinding skips this many per frame
+}
+
+impl Default for RewindSettings {
+    fn default() -> RewindSettings {
+        RewindSettings {
+            length: 10.0,
+            interval: 2,
+        }
+    }
+}
+
+// the bytes a snapshot had where the next one differs
+#[derive(Debug, Clone)]
+enum Delta {
+    Patch(Vec<(usize, Vec<u8>)>),
+    Full(Vec<u8>), // only when the encoded snapshots differ in size
+}
+
+impl Delta {
+    fn between(older: &[u8], newer: &[u8]) -> Delta {
+        if older.len() != newer.len() {
+            return Delta::Full(older.to_vec());
+        }
+
+        let mut runs = Vec::new();
+        let mut offset = 0;
+        while offset < older.len() {
+            if older[offset] == newer[offset] {
+                offset += 1;
+                continue;
+            }
+            let start = offset;
+            while offset < older.len() && older[offset] != newer[offset] {
+                offset += 1;
+            }
+            runs.push((start, older[start..offset].to_vec()));
+        }
+        Delta::Patch(runs)
+    }
+
+    // turns the newer snapshot back into the older one
+    fn apply(self, newer: Vec<u8>) -> Vec<u8> {
+        match self {
+            Delta::Patch(runs) => {
+                let mut older = newer;
+                for (offset, bytes) in runs {
+                    older[offset..offset + bytes.len()].copy_from_slice(&bytes);
+                }
+                older
+            }
+            Delta::Full(older) => older,
+        }
+    }
+}
+
+// a ring buffer of snapshots, the newest kept whole and every older one as the
+// delta that turns the snapshot after it back into it
+pub struct Rewind {
+    settings: RewindSettings,
+    frames: usize, // frames since the last snapshot
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+impl Rewind {
+    pub fn new(settings: RewindSettings) -> Rewind {
+        Rewind {
+            settings: RewindSettings {
+                interval: settings.interval.max(1),
+                ..settings
+            },
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        (self.settings.length * 60.0 / self.settings.interval as f64).ceil() as usize
+    }
+
+    // call once per frame while the program runs
+    pub fn record(&mut self, chip8: &Chip8) {
+        self.frames += 1;
+        if self.latest.is_some() && self.frames < self.settings.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let snapshot = chip8.save_state().to_bytes();
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(Delta::between(&latest, &snapshot));
+            if self.deltas.len() > self.capacity() {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(snapshot);
+    }
+
+    // go back one snapshot, returns false once the buffer runs out
+    pub fn rewind(&mut self, chip8: &mut Chip8) -> bool {
+        let (delta, latest) = match (self.deltas.pop_back(), self.latest.take()) {
+            (Some(delta), Some(latest)) => (delta, latest),
+            (_, latest) => {
+                self.latest = latest;
+                return false;
+            }
+        };
+
+        let snapshot = delta.apply(latest);
+        let state = SaveState::from_bytes(&snapshot).expect("rewind snapshots always decode");
+        chip8
+            .load_state(&state)
+            .expect("rewind snapshots always fit the machine");
+
+        // recording picks up again from the restored snapshot
+        self.latest = Some(snapshot);
+        self.frames = 0;
+        true
+    }
+
+    // forget everything, e.g. after loading a save state
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Preset, Quirks};
+
+    fn chip8() -> Chip8 {
+        // V0 += 1, DT = V0, forever
+        let program = vec![0x70, 0x01, 0xF0, 0x15, 0x12, 0x00];
+        Chip8::new(program, Quirks::new(Preset::Modern)).unwrap()
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let older = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let newer = vec![1, 9, 9, 4, 5, 6, 9, 8];
+        let delta = Delta::between(&older, &newer);
+        match &delta {
+            Delta::Patch(runs) => assert_eq!(runs, &[(1, vec![2, 3]), (6, vec![7])]),
+            Delta::Full(_) => panic!("snapshots of the same size get a patch"),
+        }
+        assert_eq!(delta.apply(newer.clone()), older);
+
+        // nothing changed
+        let delta = Delta::between(&older, &older);
+        assert!(matches!(&delta, Delta::Patch(runs) if runs.is_empty()));
+        assert_eq!(delta.apply(older.clone()), older);
+
+        // the encoded size changed, e.g. with the display resolution
+        let delta = Delta::between(&older, &newer[..5]);
+        assert!(matches!(delta, Delta::Full(_)));
+        assert_eq!(delta.apply(newer[..5].to_vec()), older);
+    }
+
+    #[test]
+    fn evicts_the_oldest_snapshot() {
+        // room for 5 snapshots before the newest
+        let settings = RewindSettings {
+            length: 5.0 / 60.0,
+            interval: 1,
+        };
+        let mut rewind = Rewind::new(settings);
+        let mut chip8 = chip8();
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            chip8.run_frame().unwrap();
+            rewind.record(&chip8);
+            states.push(chip8.save_state());
+        }
+        assert_eq!(rewind.deltas.len(), 5);
+
+        for _ in 0..5 {
+            assert!(rewind.rewind(&mut chip8));
+        }
+        assert_eq!(chip8.save_state(), states[4]);
+        assert!(!rewind.rewind(&mut chip8));
+        assert_eq!(chip8.save_state(), states[4]);
+    }
+
+    #[test]
+    fn rewinds_to_earlier_states() {
+        let mut rewind = Rewind::new(RewindSettings::default());
+        let mut chip8 = chip8();
+        // the first frame and then every `interval` frames get a snapshot
+        let mut snapshots = Vec::new();
+        for frame in 0..21 {
+            chip8.run_frame().unwrap();
+            rewind.record(&chip8);
+            if frame % 2 == 0 {
+                snapshots.push(chip8.save_state());
+            }
+        }
+
+        for back in 1..snapshots.len() {
+            assert!(rewind.rewind(&mut chip8));
+            assert_eq!(
+                chip8.save_state(),
+                snapshots[snapshots.len() - 1 - back],
+                "{} back",
+                back
+            );
+        }
+        assert!(!rewind.rewind(&mut chip8));
+
+        // recording carries on from the restored snapshot
+        chip8.run_frame().unwrap();
+        rewind.record(&chip8);
+        chip8.run_frame().unwrap();
+        rewind.record(&chip8);
+        assert!(rewind.rewind(&mut chip8));
+        assert_eq!(chip8.save_state(), snapshots[0]);
+    }
+}
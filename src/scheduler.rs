@@ -0,0 +1,27 @@
+pub const FRAME_RATE: f64 = 60.0;
+
+// a frontend that falls further behind than this (a stalled window, a slow
+// machine) skips ahead instead of running all the frames it missed at once
+const MAX_FRAMES_BEHIND: usize = 4;
+
+// turns the time between a frontend's updates into whole 60Hz frames, carrying
+// what's left over to the next update so the speed doesn't depend on how
+// often the frontend updates
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    time: f64, // seconds not yet turned into frames
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    // the number of frames to run after `delta` more seconds
+    pub fn frames(&mut self, delta: f64) -> usize {
+        self.time += delta;
+        let frames = (self.time * FRAME_RATE).floor();
+        self.time -= frames / FRAME_RATE;
+        (frames as usize).min(MAX_FRAMES_BEHIND)
+    }
+}
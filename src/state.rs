@@ -0,0 +1,236 @@
+use rand_pcg::Pcg32;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+// bumped whenever the layout of `SaveState` changes
+pub const STATE_VERSION: u32 = 5;
+
+// binary save states start with these bytes, anything else is read as JSON
+pub const STATE_MAGIC: &[u8; 4] = b"RC8S";
+
+// a snapshot of the whole machine, see `Chip8::save_state`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveState {
+    pub version: u32,
+    pub pc: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub frame_cycles: u32, // VIP timing: machine cycles since the display interrupt
+    pub cycle_budget: f64, // instructions (VIP machine cycles) left to run in this frame
+    pub memory: Vec<u8>,
+    pub keyboard: [bool; 16],
+    pub keyboard_wait_key: Option<u8>,
+    pub keyboard_wait_pressed: Option<u8>,
+    pub vblank_wait: bool,
+    pub rpl: [u8; 16],
+    pub audio_pattern: Option<[u8; 16]>,
+    pub pitch: u8,
+    pub halted: bool,
+    pub rng: Pcg32,
+    pub display: Vec<u8>,
+    pub hires: bool,
+    pub planes: u8,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    // the data isn't a save state
+    Invalid(String),
+    // the state was saved by another version of the emulator
+    UnsupportedVersion(u32),
+    // the state was saved with a different memory size (XO-CHIP vs the rest)
+    MemorySize { expected: usize, found: usize },
+}
+
+impl SaveState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = STATE_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(self).expect("save states always serialize"));
+        bytes
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("save states always serialize")
+    }
+
+    // read a state in either format
+    pub fn from_bytes(data: &[u8]) -> Result<SaveState, StateError> {
+        let state: SaveState = match data.strip_prefix(STATE_MAGIC) {
+            Some(data) => {
+                // the version goes first so it can be checked before the rest is decoded
+                let version: u32 = bincode::deserialize(data).map_err(invalid)?;
+                if version != STATE_VERSION {
+                    return Err(StateError::UnsupportedVersion(version));
+                }
+                bincode::deserialize(data).map_err(invalid)?
+            }
+            None => {
+                let value: serde_json::Value = serde_json::from_slice(data).map_err(invalid)?;
+                let version = value["version"]
+                    .as_u64()
+                    .ok_or_else(|| StateError::Invalid("missing version".to_string()))?;
+                if version != STATE_VERSION as u64 {
+                    return Err(StateError::UnsupportedVersion(version as u32));
+                }
+                serde_json::from_value(value).map_err(invalid)?
+            }
+        };
+        Ok(state)
+    }
+}
+
+fn invalid<E: fmt::Display>(error: E) -> StateError {
+    StateError::Invalid(error.to_string())
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Invalid(e) => write!(f, "invalid save state: {}", e),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} isn't supported (expected {})",
+                version, STATE_VERSION
+            ),
+            StateError::MemorySize { expected, found } => write!(
+                f,
+                "save state has {} bytes of memory but the machine has {} (check --quirks)",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chip8, Preset, Quirks};
+
+    // a machine in the middle of a program: registers, stack, display and timers
+    // in use, and Fx0A waiting for a held key to be released
+    fn running_chip8() -> Chip8 {
+        let program = vec![
+            0x60, 0x12, // LD V0, 0x12
+            0xA2, 0x10, // LD I, 0x210
+            0x22, 0x0A, // CALL 0x20A
+            0x12, 0x06, // JP 0x206
+            0x00, 0x00, //
+            0xF0, 0x15, // LD DT, V0
+            0xD0, 0x05, // DRW V0, V0, 5
+            0xF1, 0x0A, // LD V1, K
+            0x00, 0xEE, // RET
+        ];
+        let mut chip8 = Chip8::new(program, Quirks::new(Preset::Vip)).unwrap();
+        chip8.set_seed(7);
+        chip8.run_frame().unwrap();
+        chip8.handle_key_down(3);
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.stack(), &[0x206]);
+        assert_eq!(chip8.key_wait().unwrap().pressed, Some(3));
+        chip8
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut chip8 = running_chip8();
+        let state = chip8.save_state();
+        let loaded = SaveState::from_bytes(&state.to_bytes()).unwrap();
+        assert_eq!(loaded, state);
+
+        let mut other = Chip8::new(Vec::new(), Quirks::new(Preset::Vip)).unwrap();
+        other.load_state(&loaded).unwrap();
+        assert_eq!(other.save_state(), state);
+
+        // both machines carry on the same way
+        chip8.run_frame().unwrap();
+        other.run_frame().unwrap();
+        assert_eq!(other.save_state(), chip8.save_state());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let chip8 = running_chip8();
+        let state = chip8.save_state();
+        let loaded = SaveState::from_bytes(state.to_json().as_bytes()).unwrap();
+        assert_eq!(loaded, state);
+
+        let mut other = Chip8::new(Vec::new(), Quirks::new(Preset::Vip)).unwrap();
+        other.load_state(&loaded).unwrap();
+        assert_eq!(other.save_state(), state);
+    }
+
+    fn edited(edit: impl FnOnce(&mut SaveState)) -> Result<(), StateError> {
+        let mut chip8 = running_chip8();
+        let mut state = chip8.save_state();
+        edit(&mut state);
+        // through JSON, the way a hand-edited state arrives
+        let state = SaveState::from_bytes(state.to_json().as_bytes())?;
+        chip8.load_state(&state)
+    }
+
+    #[test]
+    fn rejects_registers_out_of_range() {
+        assert!(matches!(
+            edited(|state| state.keyboard_wait_key = Some(200)),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(matches!(
+            edited(|state| state.keyboard_wait_pressed = Some(16)),
+            Err(StateError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_addresses_out_of_memory() {
+        assert!(matches!(
+            edited(|state| state.pc = 0x1000),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(matches!(
+            edited(|state| state.i = 0xFFFF),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(matches!(
+            edited(|state| state.stack[15] = 0x2000),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(matches!(
+            edited(|state| state.sp = 17),
+            Err(StateError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_frame_timing_out_of_range() {
+        assert!(matches!(
+            edited(|state| state.frame_cycles = u32::MAX - 10),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(matches!(
+            edited(|state| state.cycle_budget = 1e18),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(matches!(
+            edited(|state| state.cycle_budget = -1e18),
+            Err(StateError::Invalid(_))
+        ));
+        assert!(edited(|state| state.cycle_budget = 0.5).is_ok());
+    }
+
+    #[test]
+    fn rejected_state_leaves_the_machine_alone() {
+        let mut chip8 = running_chip8();
+        let before = chip8.save_state();
+        let mut state = before.clone();
+        state.keyboard_wait_key = Some(200);
+        assert!(chip8.load_state(&state).is_err());
+        assert_eq!(chip8.save_state(), before);
+    }
+}